@@ -9,6 +9,10 @@ pub struct ZConfig {
     pub use_trade: bool,
     pub debug: bool,
     pub database: DatabaseAccount,
+    #[serde(default)]
+    pub instruments: Option<String>, // json file of instrument specs
+    #[serde(default)]
+    pub data_dir: Option<String>, // directory of the latency files of backtests
 }
 
 impl ZConfig {
@@ -0,0 +1,276 @@
+use crate::engine::index_key;
+use crate::market::*;
+use crate::round::round6;
+use sonic_rs::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub enum AlgoType {
+    #[default]
+    Twap, // equal slices over time
+    Vwap, // slices follow the traded volume
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub enum AlgoState {
+    #[default]
+    Running,
+    Finished,
+    Canceled,
+    Expired, // not filled by the end time
+}
+
+// parent order, the engine splits it into child orders between start_time and end_time
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AlgoOrder {
+    pub algo_id: String,
+    pub algo_type: AlgoType,
+    #[serde(default)]
+    pub contract_type: ContractType,
+    #[serde(default)]
+    pub position_side: PositionSide,
+    #[serde(default = "crate::market::default_leverage", skip_serializing)]
+    pub leverage: u32,
+    #[serde(skip_serializing)]
+    pub exchange: Exchange,
+    #[serde(skip_serializing)]
+    pub symbol: String,
+    pub side: OrderSide,
+    pub amount: f64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub interval: i64, // time between two slices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_price: Option<f64>, // children are never priced through it
+    #[serde(default)]
+    pub participation: f64, // vwap only, share of the traded volume to follow
+
+    // progress, set by the engine
+    #[serde(default)]
+    pub filled_amount: f64,
+    #[serde(default)]
+    pub avg_price: f64,
+    #[serde(default)]
+    pub arrival_price: f64,
+    #[serde(default)]
+    pub slippage_bps: f64, // avg price versus arrival price, positive is a cost
+    #[serde(default)]
+    pub state: AlgoState,
+    #[serde(skip)]
+    next_slice: i64,
+    #[serde(skip)]
+    market_volume: f64, // traded volume since the last slice
+    #[serde(skip)]
+    children: Vec<String>,
+    #[serde(skip)]
+    final_slice: bool, // the rest was posted at the end time
+}
+
+impl AlgoOrder {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.amount <= 0.0 || round6(self.amount) != self.amount {
+            return Err("Invalid algo amount.".to_string());
+        }
+        if self.end_time <= self.start_time || self.interval <= 0 {
+            return Err("Invalid algo schedule.".to_string());
+        }
+        if self.algo_type == AlgoType::Vwap && (self.participation <= 0.0 || self.participation > 1.0) {
+            return Err("Invalid algo participation.".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn start(&mut self, arrival_price: f64) {
+        self.arrival_price = arrival_price;
+        self.filled_amount = 0.0;
+        self.avg_price = 0.0;
+        self.slippage_bps = 0.0;
+        self.state = AlgoState::Running;
+        self.next_slice = self.start_time;
+        self.market_volume = 0.0;
+        self.children.clear();
+        self.final_slice = false;
+    }
+
+    pub fn is_child(&self, cid: &str) -> bool {
+        self.children.iter().any(|x| x == cid)
+    }
+
+    // a posted child, or one split by the engine, e.g. the open part of a one-way flip
+    pub fn adopt(&mut self, cid: String) {
+        self.children.push(cid);
+    }
+
+    pub fn children(&self) -> &[String] {
+        &self.children
+    }
+
+    // the slice is due, the open children have to be canceled before posting the next one
+    pub fn is_due(&self, timestamp: i64) -> bool {
+        self.state == AlgoState::Running && timestamp >= self.next_slice
+    }
+
+    // called when a slice is due, the slice of the end time had its interval to fill
+    pub fn expire(&mut self) -> bool {
+        if self.final_slice && self.state == AlgoState::Running {
+            self.state = AlgoState::Expired;
+        }
+        self.state == AlgoState::Expired
+    }
+
+    // next child order, sized to catch up with the schedule, adopted once it is posted
+    pub fn slice(&mut self, depth: &Depth) -> Option<Order> {
+        let now = depth.local_timestamp;
+        self.next_slice = now + self.interval;
+        let target = if now >= self.end_time {
+            self.amount
+        } else {
+            match self.algo_type {
+                AlgoType::Twap => {
+                    let elapsed = (now - self.start_time) as f64 / (self.end_time - self.start_time) as f64;
+                    self.amount * elapsed.clamp(0.0, 1.0)
+                }
+                AlgoType::Vwap => self.filled_amount + self.participation * self.market_volume,
+            }
+        };
+        self.market_volume = 0.0;
+        self.final_slice = now >= self.end_time;
+        let amount = round6(target.min(self.amount) - self.filled_amount);
+        if amount < 1e-6 {
+            return None;
+        }
+        let mut child = Order {
+            contract_type: self.contract_type.clone(),
+            position_side: self.position_side.clone(),
+            leverage: self.leverage,
+            exchange: self.exchange,
+            cid: format!("{}-{}", self.algo_id, self.children.len()),
+            symbol: self.symbol.clone(),
+            amount,
+            side: self.side.clone(),
+            front_amount: -1.0,
+            timestamp: now,
+            ..Default::default()
+        };
+        // cross the spread, unless the limit price is better
+        child.price = if child.is_buy_direction() {
+            let ask = depth.asks.first()?.0;
+            self.limit_price.map_or(ask, |limit| ask.min(limit))
+        } else {
+            let bid = depth.bids.first()?.0;
+            self.limit_price.map_or(bid, |limit| bid.max(limit))
+        };
+        Some(child)
+    }
+
+    pub fn on_trade(&mut self, trade: &Trade) {
+        if self.state == AlgoState::Running
+            && trade.local_timestamp >= self.start_time
+            && Exchange::from_name(&trade.exchange) == Some(self.exchange)
+            && index_key(&trade.symbol) == index_key(&self.symbol)
+        {
+            self.market_volume += trade.amount;
+        }
+    }
+
+    pub fn on_fill(&mut self, price: f64, amount: f64, buy_direction: bool) {
+        let amount = amount.abs();
+        if amount <= 0.0 {
+            return;
+        }
+        self.avg_price = (self.avg_price * self.filled_amount + price * amount) / (self.filled_amount + amount);
+        self.filled_amount = round6(self.filled_amount + amount);
+        if self.arrival_price > 0.0 {
+            let sign = if buy_direction { 1.0 } else { -1.0 };
+            self.slippage_bps = sign * (self.avg_price - self.arrival_price) / self.arrival_price * 1e4;
+        }
+        if self.filled_amount >= self.amount {
+            self.state = AlgoState::Finished;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_twap_slices() {
+        let mut algo = AlgoOrder {
+            algo_id: "twap".to_string(),
+            amount: 1.0,
+            start_time: 0,
+            end_time: 100,
+            interval: 25,
+            limit_price: Some(101.0),
+            ..Default::default()
+        };
+        algo.validate().unwrap();
+        algo.start(100.0);
+        let mut depth = Depth {
+            bids: vec![(99.0, 1.0)],
+            asks: vec![(102.0, 1.0)],
+            local_timestamp: 50,
+            ..Default::default()
+        };
+        assert!(algo.is_due(depth.local_timestamp));
+        let child = algo.slice(&depth).unwrap();
+        assert_eq!(child.amount, 0.5);
+        assert_eq!(child.price, 101.0);
+        algo.adopt(child.cid.clone());
+        assert!(algo.is_child(&child.cid));
+        assert!(!algo.is_due(60));
+
+        algo.on_fill(101.0, 0.5, true);
+        assert_eq!(algo.slippage_bps, 100.0);
+        // behind the schedule at the end, the rest goes in one slice
+        depth.local_timestamp = 100;
+        let child = algo.slice(&depth).unwrap();
+        assert_eq!(child.amount, 0.5);
+        algo.on_fill(101.0, 0.5, true);
+        assert_eq!(algo.state, AlgoState::Finished);
+    }
+
+    #[test]
+    fn test_vwap_volume_and_expiry() {
+        let mut algo = AlgoOrder {
+            algo_id: "vwap".to_string(),
+            algo_type: AlgoType::Vwap,
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            amount: 1.0,
+            start_time: 0,
+            end_time: 100,
+            interval: 25,
+            participation: 0.5,
+            ..Default::default()
+        };
+        algo.validate().unwrap();
+        algo.start(100.0);
+        let trade = |exchange: &str, symbol: &str| Trade {
+            exchange: exchange.to_string(),
+            symbol: symbol.to_string(),
+            amount: 1.0,
+            local_timestamp: 10,
+            ..Default::default()
+        };
+        // only the prints of its own market count
+        algo.on_trade(&trade("binance-futures", "BTCUSDT"));
+        algo.on_trade(&trade("binance-futures", "ETHUSDT"));
+        algo.on_trade(&trade("okx_futures", "BTC-USDT"));
+        let mut depth = Depth {
+            bids: vec![(99.0, 1.0)],
+            asks: vec![(101.0, 1.0)],
+            local_timestamp: 50,
+            ..Default::default()
+        };
+        assert_eq!(algo.slice(&depth).unwrap().amount, 0.5);
+
+        // the last slice is not filled, the algo expires at the next one
+        depth.local_timestamp = 100;
+        assert_eq!(algo.slice(&depth).unwrap().amount, 1.0);
+        assert!(!algo.is_due(110));
+        assert!(algo.is_due(125));
+        assert!(algo.expire());
+        assert!(!algo.is_due(150));
+    }
+}
@@ -1,16 +1,21 @@
+use crate::algo::{AlgoOrder, AlgoState, AlgoType};
 use crate::dataloader::DataSource;
+use crate::impact::{ImpactModel, LiquidityBook};
+use crate::instrument::{InstrumentSpec, Instruments, Reject};
+use crate::queue::{QueueModel, QueueModelType};
 use crate::{market::*, ZConfig};
 use crate::round::round6;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 // use rustc_hash::FxHashMap;
-use super::{dataloader::DataLoader, server::BacktestResponse};
+use super::{dataloader::DataLoader, server::{BacktestResponse, BacktestStatus}};
 use log::{debug, info};
 use rand_distr::{Distribution, Normal};
 use sonic_rs::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::i64;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -25,42 +30,155 @@ pub enum BacktestState {
     Finished,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum LatencyModel {
     None,
     Fixed(i64),
     Random(i64, i64),
     Positivedistribution(f64, f64),
+    // recorded latencies, drawn with equal probability
+    Empirical(Vec<i64>),
+    // (latency, weight) buckets
+    Histogram(Vec<(i64, f64)>),
+    // recorded latencies, one per line, loaded at launch from the data_dir of the server
+    File {
+        path: String,
+        #[serde(skip)]
+        samples: Vec<i64>,
+    },
+    // (start hour of the day in utc, model), microsecond timestamps
+    TimeOfDay(Vec<(u32, LatencyModel)>),
+    // (messages per second from, model), the busier the market data the slower
+    MessageRate(Vec<(f64, LatencyModel)>),
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        LatencyModel::Fixed(20)
+    }
 }
 
 impl LatencyModel {
-    pub fn order_with_latency(&self, order: Order) -> Order {
+    pub fn validate(&self) -> Result<(), String> {
         match self {
-            LatencyModel::None => order,
-            LatencyModel::Fixed(latency) => Order {
-                timestamp: order.timestamp + latency,
-                ..order
-            },
-            LatencyModel::Random(a, b) => Order {
-                timestamp: order.timestamp + rand::thread_rng().gen_range(*a..*b),
-                ..order
-            },
+            LatencyModel::None => Ok(()),
+            LatencyModel::Fixed(latency) if *latency >= 0 => Ok(()),
+            LatencyModel::Random(a, b) if 0 <= *a && a < b => Ok(()),
+            LatencyModel::Positivedistribution(mean, std) if mean.is_finite() && std.is_finite() && *std > 0.0 => Ok(()),
+            LatencyModel::Empirical(samples) if !samples.is_empty() && samples.iter().all(|x| *x >= 0) => Ok(()),
+            LatencyModel::File { path, samples } if !path.is_empty() && samples.iter().all(|x| *x >= 0) => Ok(()),
+            LatencyModel::Histogram(buckets)
+                if buckets.iter().all(|(latency, weight)| *latency >= 0 && weight.is_finite() && *weight >= 0.0)
+                    && buckets.iter().map(|x| x.1).sum::<f64>() > 0.0 => Ok(()),
+            LatencyModel::TimeOfDay(buckets)
+                if !buckets.is_empty() && buckets.iter().all(|(hour, _)| *hour < 24) =>
+            {
+                buckets.iter().try_for_each(|(_, model)| model.validate())
+            }
+            LatencyModel::MessageRate(buckets)
+                if !buckets.is_empty() && buckets.iter().all(|(rate, _)| rate.is_finite() && *rate >= 0.0) =>
+            {
+                buckets.iter().try_for_each(|(_, model)| model.validate())
+            }
+            _ => Err(format!("Invalid latency model: {:?}", self)),
+        }
+    }
+
+    // read the recorded latencies of file models, their paths are relative to the data directory
+    pub fn load(&mut self, data_dir: Option<&str>) -> Result<(), String> {
+        match self {
+            LatencyModel::File { path, samples } => {
+                let dir = data_dir.ok_or("Latency files need data_dir in the server config.")?;
+                let dir = std::fs::canonicalize(dir).map_err(|_| "Invalid data_dir in the server config.")?;
+                // no way out of the data directory
+                let file = std::fs::canonicalize(dir.join(&*path))
+                    .ok()
+                    .filter(|x| x.starts_with(&dir))
+                    .ok_or_else(|| format!("Latency file {} not found.", path))?;
+                let content = std::fs::read_to_string(file).map_err(|_| format!("Failed to read latency file {}.", path))?;
+                *samples = content
+                    .lines()
+                    .enumerate()
+                    .map(|(index, line)| (index, line.trim()))
+                    .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+                    .map(|(index, line)| {
+                        line.parse::<f64>()
+                            .map(|x| x.round() as i64)
+                            .map_err(|_| format!("Invalid latency on line {} of {}.", index + 1, path))
+                    })
+                    .collect::<Result<_, _>>()?;
+                if samples.is_empty() {
+                    return Err(format!("No latency in {}.", path));
+                }
+                Ok(())
+            }
+            LatencyModel::TimeOfDay(buckets) => buckets.iter_mut().try_for_each(|(_, model)| model.load(data_dir)),
+            LatencyModel::MessageRate(buckets) => buckets.iter_mut().try_for_each(|(_, model)| model.load(data_dir)),
+            _ => Ok(()),
+        }
+    }
+
+    // timestamp of the message and market data messages per second, for the bucketed models
+    pub fn latency<R: Rng + ?Sized>(&self, timestamp: i64, rate: f64, rng: &mut R) -> i64 {
+        match self {
+            LatencyModel::None => 0,
+            LatencyModel::Fixed(latency) => *latency,
+            LatencyModel::Random(a, b) => rng.gen_range(*a..*b),
             LatencyModel::Positivedistribution(mean, std) => {
                 let normal = Normal::new(*mean, *std).map_err(|e| {
                     log::error!("Normal distribution creation failed: {:?}", e);
                     e
                 }).unwrap_or_else(|_| Normal::new(0.0, 1.0).unwrap());
-                let latency = normal.sample(&mut rand::thread_rng()).max(0.0); // Ensure latency is non-negative
-                Order {
-                    timestamp: order.timestamp + latency as i64,
-                    ..order
+                normal.sample(rng).max(0.0) as i64 // Ensure latency is non-negative
+            }
+            LatencyModel::Empirical(samples) | LatencyModel::File { samples, .. } => {
+                if samples.is_empty() {
+                    return 0;
+                }
+                samples[rng.gen_range(0..samples.len())]
+            }
+            LatencyModel::Histogram(buckets) => {
+                let mut pick = rng.gen_range(0.0..1.0) * buckets.iter().map(|x| x.1).sum::<f64>();
+                for (latency, weight) in buckets {
+                    if pick < *weight {
+                        return *latency;
+                    }
+                    pick -= weight;
                 }
+                buckets.last().map_or(0, |x| x.0)
+            }
+            LatencyModel::TimeOfDay(buckets) => {
+                let hour = (timestamp.rem_euclid(86_400_000_000) / 3_600_000_000) as u32;
+                // the latest bucket started today, or the last one of yesterday
+                buckets
+                    .iter()
+                    .filter(|x| x.0 <= hour)
+                    .max_by_key(|x| x.0)
+                    .or_else(|| buckets.iter().max_by_key(|x| x.0))
+                    .map_or(0, |(_, model)| model.latency(timestamp, rate, rng))
             }
+            LatencyModel::MessageRate(buckets) => buckets
+                .iter()
+                .filter(|x| x.0 <= rate)
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map_or(0, |(_, model)| model.latency(timestamp, rate, rng)),
+        }
+    }
+
+    pub fn order_with_latency<R: Rng + ?Sized>(&self, order: Order, rate: f64, rng: &mut R) -> Order {
+        Order {
+            timestamp: order.timestamp + self.latency(order.timestamp, rate, rng),
+            ..order
         }
     }
+
+    pub fn is_none(&self) -> bool {
+        *self == LatencyModel::None
+    }
 }
 
 // back = backQueuePosition , front = nowQueuePosition
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum FillModel {
     None,
     Random(f64, f64),
@@ -81,11 +199,29 @@ pub enum FillModel {
     LogProbQueueFunc,
 }
 
+impl Default for FillModel {
+    fn default() -> Self {
+        FillModel::PowerProbQueueFunc3(3.0)
+    }
+}
+
 impl FillModel {
-    pub fn prob(&self, back: f64, front: f64) -> f64 {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            FillModel::Random(min, max) if 0.0 <= *min && min < max && *max <= 1.0 => Ok(()),
+            FillModel::PowerProbQueueFunc(n)
+            | FillModel::PowerProbQueueFunc2(n)
+            | FillModel::PowerProbQueueFunc3(n)
+                if n.is_finite() && *n > 0.0 => Ok(()),
+            FillModel::None | FillModel::LogProbQueueFunc | FillModel::LogProbQueueFunc2 => Ok(()),
+            _ => Err(format!("Invalid fill model: {:?}", self)),
+        }
+    }
+
+    pub fn prob<R: Rng + ?Sized>(&self, back: f64, front: f64, rng: &mut R) -> f64 {
         match self {
             FillModel::None => 1.0,
-            FillModel::Random(min, max) => rand::thread_rng().gen_range(*min..*max),
+            FillModel::Random(min, max) => rng.gen_range(*min..*max),
             FillModel::PowerProbQueueFunc(n) => {
                 let back_power = back.powf(*n);
                 let front_power = front.powf(*n);
@@ -121,9 +257,100 @@ pub struct BtConfig {
     pub source: Option<DataSource>,
     pub balance: Balance,
     pub fee_rate: FeeRate,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impact: Option<ImpactModel>,
+    #[serde(default)]
+    pub latency: LatencyModel, // order entry
+    #[serde(default = "no_latency")]
+    pub feed_latency: LatencyModel, // market data to the client, delays orders and cancels
+    #[serde(default = "no_latency")]
+    pub cancel_latency: LatencyModel,
+    #[serde(default = "no_latency")]
+    pub ack_latency: LatencyModel, // order and fill reports back to the client
+    #[serde(default)]
+    pub fill_model: FillModel,
+    #[serde(default)]
+    pub queue_model: QueueModelType,
+    #[serde(default)]
+    pub position_mode: PositionMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub funding: Option<Funding>,
+    // currency of balance, the quote of symbol when missing, spot symbols quoted in other currencies use the wallet
+    #[serde(default)]
+    pub currency: String,
+    // initial holdings by currency, spot inventory and inverse margin
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub wallet: HashMap<String, Balance>,
+    // instrument specs on top of the zconfig instruments file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instruments: Vec<InstrumentSpec>,
+    // seed of the random latencies and fills, set on launch when missing so the run can be reproduced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // deposits and withdrawals during the backtest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transfers: Vec<Transfer>,
+}
+
+// BTC_USDT, btc-usdt and BTCUSDT are the same index
+pub(crate) fn index_key(symbol: &str) -> String {
+    symbol.to_uppercase().replace(['_', '-', '/'], "")
+}
+
+fn no_latency() -> LatencyModel {
+    LatencyModel::None
 }
 
 impl BtConfig {
+    // check the simulation models before launching
+    pub fn validate(&self) -> Result<(), String> {
+        self.latency.validate()?;
+        self.feed_latency.validate()?;
+        self.cancel_latency.validate()?;
+        self.ack_latency.validate()?;
+        self.fill_model.validate()?;
+        if self.queue_model == QueueModelType::Trade && self.fill_model != FillModel::default() {
+            return Err("fill_model has no effect with the Trade queue model.".to_string());
+        }
+        for spec in self.instruments.iter() {
+            spec.validate()?;
+        }
+        if self.transfers.iter().any(|x| !x.amount.is_finite() || x.amount == 0.0) {
+            return Err("Invalid transfer amount.".to_string());
+        }
+        if let Some(impact) = &self.impact {
+            if !impact.temporary.is_finite() || !impact.permanent.is_finite() || impact.half_life < 0 {
+                return Err(format!("Invalid impact model: {:?}", impact));
+            }
+            // without a half life the temporary impact would be gone at once
+            if impact.temporary != 0.0 && impact.half_life == 0 {
+                return Err("The temporary impact needs a half_life.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn balance_currency(&self) -> String {
+        if self.currency.is_empty() {
+            return split_symbol(&self.symbol).1;
+        }
+        self.currency.clone()
+    }
+
+    // checks against the server config and the files of the backtest, at launch after validate
+    pub fn prepare(&mut self, zconfig: &ZConfig) -> Result<(), String> {
+        // echoed back with the launch
+        self.currency = self.balance_currency();
+        if self.queue_model == QueueModelType::Trade && !zconfig.use_trade {
+            return Err("The Trade queue model needs use_trade.".to_string());
+        }
+        for model in [&mut self.latency, &mut self.feed_latency, &mut self.cancel_latency, &mut self.ack_latency] {
+            model.load(zconfig.data_dir.as_deref())?;
+        }
+        Instruments::load(zconfig.instruments.as_deref(), &self.instruments)?;
+        Ok(())
+    }
+
     pub fn parse(config: &str) -> BtConfig {
         sonic_rs::from_str(config).unwrap_or_else(|_| {
             log::error!("Failed to parse config");
@@ -149,7 +376,57 @@ impl OrderList {
         None
     }
 
-    pub fn execute_orders(&mut self, depth: &Depth, fill_model: FillModel) -> Vec<FilledStack> {
+    pub fn get_open_mut(&mut self, cid: &str) -> Option<&mut Order> {
+        self.inner.iter_mut().find(|x| {
+            x.cid == cid && (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+        })
+    }
+
+    // cids of the open orders in an oco group, except the given one
+    pub fn oco_siblings(&self, group: &str, cid: &str) -> Vec<String> {
+        self.inner
+            .iter()
+            .filter(|x| {
+                x.oco_group.as_deref() == Some(group)
+                    && x.cid != cid
+                    && (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+            })
+            .map(|x| x.cid.clone())
+            .collect()
+    }
+
+    // take out the stop orders whose trigger price is crossed by the depth
+    pub fn take_triggered(&mut self, depth: &Depth) -> Vec<Order> {
+        let mut triggered = vec![];
+        self.inner.retain(|order| {
+            if order.state == OrderState::Open
+                && order.exchange == depth.exchange
+                && depth.local_timestamp >= order.timestamp
+                && order.is_stop_triggered(depth)
+            {
+                triggered.push(order.clone());
+                return false;
+            }
+            true
+        });
+        triggered
+    }
+
+    pub fn execute_orders(
+        &mut self,
+        depth: &Depth,
+        trades: &[Trade],
+        queue_model: &dyn QueueModel,
+        liquidity: &mut LiquidityBook,
+        rng: &mut dyn RngCore,
+    ) -> Vec<FilledStack> {
+        // orders can't take the same liquidity twice
+        let mut available = liquidity.available(depth);
+        let trades: Vec<Trade> = trades
+            .iter()
+            .filter(|x| Exchange::from_name(&x.exchange) == Some(depth.exchange))
+            .cloned()
+            .collect();
         // println!("{:?}", self.inner);
         let mut filled = vec![];
         self.inner.retain_mut(|order| {
@@ -169,27 +446,35 @@ impl OrderList {
                 return true;
             }
             // info!("depth.timestamp: {:?}, order.timestamp: {:?}", depth.local_timestamp, order.timestamp);
-            if depth.local_timestamp < order.timestamp || order.exchange != depth.exchange {
-                return true; // keep this order
+            if depth.local_timestamp < order.timestamp
+                || order.exchange != depth.exchange
+                || order.stop_price.is_some()
+            {
+                return true; // keep this order, stop orders wait for trigger
+            }
+            let buy = order.is_buy_direction();
+            let (filled_price, filled_amount) =
+                match liquidity.with_impact(&available, buy, depth.local_timestamp) {
+                    Some(shifted) => order.execute_with_trades(&shifted, &trades, queue_model, rng),
+                    None => order.execute_with_trades(&available, &trades, queue_model, rng),
+                };
+            let levels = if buy { &mut available.asks } else { &mut available.bids };
+            let mut taken_amount = 0.0;
+            for (index, amount) in order.taken_levels.drain(..) {
+                let price = levels[index].0;
+                levels[index].1 = ((levels[index].1 - amount) * 1e6).round() / 1e6;
+                liquidity.consume(depth.exchange, !buy, price, amount, depth.level_amount(!buy, price));
+                taken_amount += amount;
+            }
+            levels.retain(|level| level.1 > 0.0);
+            if taken_amount > 0.0 {
+                liquidity.record_impact(buy, taken_amount, depth.local_timestamp);
             }
-            let (filled_price, filled_amount) = order.execute(depth, fill_model.clone());
 
             if filled_price != 0.0 {
                 filled.push(FilledStack {
-                    cid: order.cid.clone(),
-                    exchange: order.exchange,
-                    symbol: order.symbol.clone(),
-                    contract_type: order.contract_type.clone(),
-                    side: order.position_side.clone(),
-                    leverage: order.leverage,
-                    take_profit: order.take_profit.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
-                    stop_loss: order.stop_loss.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
-                    filled_price: Decimal::from_f64(filled_price).unwrap_or(dec!(0)),
-                    filled_amount: Decimal::from_f64(filled_amount).unwrap_or(dec!(0)),
-                    open_price: order.open_price.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
-                    post_price: Decimal::from_f64(order.price).unwrap_or(dec!(0)),
-                    freeze_margin: Decimal::from_f64(order.margin).unwrap_or(dec!(0)),
-                    amount_total: Decimal::from_f64(order.amount).unwrap_or(dec!(0)),
+                    maker: taken_amount == 0.0,
+                    ..FilledStack::from_order(order, filled_price, filled_amount)
                 });
             }
             true
@@ -198,22 +483,104 @@ impl OrderList {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct FilledStack {
     pub cid: String,
     pub exchange: Exchange,
     pub symbol: String,
     pub side: PositionSide,
     pub contract_type: ContractType,
+    #[serde(skip)]
     pub leverage: u32,
+    #[serde(skip)]
     pub take_profit: Option<Decimal>,
+    #[serde(skip)]
     pub stop_loss: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub open_price: Option<Decimal>,
     pub filled_price: Decimal,
-    pub filled_amount: Decimal,
+    pub filled_amount: Decimal, // positive when taking the asks
+    #[serde(skip)]
     pub post_price: Decimal,
+    #[serde(skip)]
     pub freeze_margin: Decimal,
+    #[serde(skip)]
     pub amount_total: Decimal,
+    #[serde(skip)]
+    pub oco_group: Option<String>,
+    #[serde(skip)]
+    pub margin_mode: MarginMode,
+    #[serde(skip)]
+    pub multiplier: Decimal, // contract size
+    pub maker: bool, // filled resting, not by crossing the book
+    pub fee: Decimal,
+    pub timestamp: i64, // time of the market data the fill happened at
+}
+
+impl FilledStack {
+    // value of the fill, in coin for inverse contracts
+    pub fn notional(&self) -> Decimal {
+        let amount = self.filled_amount.abs();
+        match self.contract_type {
+            ContractType::Inverse if self.filled_price > dec!(0) => amount * self.multiplier / self.filled_price,
+            ContractType::Inverse => dec!(0),
+            ContractType::Options => amount * self.multiplier * self.filled_price,
+            _ => amount * self.filled_price,
+        }
+    }
+
+    // share of the margin freezed by the order that this fill uses
+    pub fn margin_share(&self) -> Decimal {
+        if self.amount_total == dec!(0) {
+            return dec!(0);
+        }
+        (self.freeze_margin * self.filled_amount.abs() / self.amount_total).round_dp(12)
+    }
+
+    // pnl of a closing fill, in coin for inverse contracts
+    pub fn realized_pnl(&self) -> Decimal {
+        let closing = (self.side == PositionSide::Long) == (self.filled_amount < dec!(0));
+        if self.contract_type == ContractType::Spot || !closing {
+            return dec!(0);
+        }
+        let open = self.open_price.unwrap_or(self.filled_price);
+        let amount = self.filled_amount.abs();
+        let pnl = match self.contract_type {
+            ContractType::Inverse if open > dec!(0) && self.filled_price > dec!(0) => {
+                amount * self.multiplier / open - amount * self.multiplier / self.filled_price
+            }
+            ContractType::Inverse => dec!(0),
+            ContractType::Options => amount * self.multiplier * (self.filled_price - open),
+            _ => amount * (self.filled_price - open),
+        };
+        let pnl = if self.side == PositionSide::Short { -pnl } else { pnl };
+        pnl.round_dp(12)
+    }
+
+    pub fn from_order(order: &Order, filled_price: f64, filled_amount: f64) -> FilledStack {
+        FilledStack {
+            cid: order.cid.clone(),
+            exchange: order.exchange,
+            symbol: order.symbol.clone(),
+            contract_type: order.contract_type.clone(),
+            side: order.position_side.clone(),
+            leverage: order.leverage,
+            take_profit: order.take_profit.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
+            stop_loss: order.stop_loss.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
+            filled_price: Decimal::from_f64(filled_price).unwrap_or(dec!(0)),
+            filled_amount: Decimal::from_f64(filled_amount).unwrap_or(dec!(0)),
+            open_price: order.open_price.map(|v| Decimal::from_f64(v).unwrap_or(dec!(0))),
+            post_price: Decimal::from_f64(order.price).unwrap_or(dec!(0)),
+            freeze_margin: Decimal::from_f64(order.margin).unwrap_or(dec!(0)),
+            amount_total: Decimal::from_f64(order.amount).unwrap_or(dec!(0)),
+            oco_group: order.oco_group.clone(),
+            margin_mode: order.margin_mode.clone(),
+            multiplier: Decimal::from_f64(order.contract_size()).unwrap_or(dec!(1)),
+            maker: false,
+            fee: dec!(0),
+            timestamp: order.timestamp,
+        }
+    }
 }
 
 #[derive(Serialize, Default)]
@@ -221,6 +588,22 @@ pub struct TickResponseDepth {
     pub depth: Depth,
     pub account: Account,
     pub orders: OrderList,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub algos: Vec<AlgoOrder>,
+}
+
+// fills of CLOSE_POSITION, the rest of the order stays in the book
+#[derive(Serialize, Default)]
+pub struct CloseResponse {
+    pub cid: String,
+    pub fills: Vec<FilledStack>,
+}
+
+// responses of the ops of STEP and the tick they are followed by
+#[derive(Serialize)]
+pub struct StepResponse {
+    pub results: Vec<BacktestResponse>,
+    pub tick: sonic_rs::Value,
 }
 
 #[derive(Serialize, Default)]
@@ -228,38 +611,102 @@ pub struct TickResponseTrade {
     pub trade: Trade,
     pub account: Account,
     pub orders: OrderList,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub algos: Vec<AlgoOrder>,
+}
+
+// the state delivered to the client with a tick, the GET_ commands are served from it
+#[derive(Clone, Default)]
+struct ClientView {
+    account: Account,
+    orders: OrderList,
+    fills: usize, // fills logged when the state was taken
+    depth: Depth, // the book of the tick, not delayed by the ack latency
 }
 
+// fills kept for GET_FILLS, the oldest are dropped past it
+const FILL_LOG_CAP: usize = 10_000;
+
 // v1, do not support hedge backtest
 pub struct ZileanV1 {
     pub config: BtConfig,
     pub zconfig: ZConfig,
     // trade: Trade,
     order_list: OrderList,
+    algos: Vec<AlgoOrder>,
     pub account: Account,
     data_loader: Arc<Mutex<DataLoader>>,
     data_cache: VecDeque<Depth>,
     trade_cache: VecDeque<Trade>,
     pub next_tick: String,
     latency: LatencyModel,
-    fill_model: FillModel,
+    instruments: Instruments,
+    rng: StdRng, // every random draw of the simulation, seeded from config.seed
+    queue_model: Box<dyn QueueModel>,
+    recent_trades: Vec<Trade>, // trades since the last match, for the queue model
+    liquidity: LiquidityBook,
+    pending_cancels: Vec<(i64, String)>,
+    pending_amends: Vec<(i64, AmendOrder)>, // applied when they reach the exchange, like the cancels
+    message_times: VecDeque<i64>, // market data timestamps of the last second
+    marks: HashMap<(Exchange, String), f64>, // mid price by exchange and index key of the symbol
+    next_funding: i64,
+    leverages: HashMap<(String, PositionSide, Exchange), u32>, // set by SET_LEVERAGE, orders of the position take it
+    transfers: VecDeque<Transfer>, // scheduled deposits and withdrawals, by timestamp
+    indexes: HashMap<String, f64>, // mid price by symbol, for the settlement of options
+    last_prices: HashMap<String, f64>, // last trade price by symbol
+    expired: HashSet<(Exchange, String)>, // options and dated futures settled at expiry
+    fills: VecDeque<FilledStack>, // the last FILL_LOG_CAP fills of the backtest, for GET_FILLS
+    fill_count: usize, // fills logged since the start, dropped ones included
+    reports: VecDeque<(i64, ClientView)>,
+    next_view: Option<ClientView>, // state of the tick computed ahead, not delivered yet
+    view: ClientView,
     state: BacktestState,
     depth: Depth,
 }
 
 impl ZileanV1 {
     pub async fn new(config: BtConfig, zconfig: ZConfig) -> ZileanV1 {
+        // checked by prepare at launch
+        let instruments = Instruments::load(zconfig.instruments.as_deref(), &config.instruments)
+            .unwrap_or_else(|e| {
+                log::error!("{}", e);
+                Instruments::default()
+            });
         Self {
             config: config.clone(),
             zconfig: zconfig.clone(),
             // trade: Trade::default(),
             order_list: OrderList::default(),
+            algos: Vec::new(),
             account: Account::default(),
             data_loader: Arc::new(Mutex::new(DataLoader::new(50_000, &config, zconfig).await)),
             data_cache: VecDeque::new(),
             trade_cache: VecDeque::new(),
-            latency: LatencyModel::Fixed(20),
-            fill_model: FillModel::PowerProbQueueFunc3(3.0),
+            latency: config.latency.clone(),
+            instruments,
+            rng: StdRng::seed_from_u64(config.seed.unwrap_or_else(rand::random)),
+            queue_model: config.queue_model.build(&config.fill_model),
+            recent_trades: Vec::new(),
+            liquidity: LiquidityBook::new(config.impact.clone()),
+            pending_cancels: Vec::new(),
+            pending_amends: Vec::new(),
+            message_times: VecDeque::new(),
+            marks: HashMap::new(),
+            next_funding: 0,
+            leverages: HashMap::new(),
+            indexes: HashMap::new(),
+            last_prices: HashMap::new(),
+            expired: HashSet::new(),
+            fills: VecDeque::new(),
+            fill_count: 0,
+            transfers: {
+                let mut transfers = config.transfers.clone();
+                transfers.sort_by_key(|x| x.timestamp);
+                VecDeque::from(transfers)
+            },
+            reports: VecDeque::new(),
+            next_view: None,
+            view: ClientView::default(),
             state: BacktestState::default(),
             depth: Depth::default(),
             next_tick: "".to_string(),
@@ -275,7 +722,10 @@ impl ZileanV1 {
         tick_url: &str,
     ) -> Result<(), std::io::Error> {
         self.account.balance = self.config.balance.clone();
+        self.account.wallet = self.config.wallet.clone();
+        self.account.currency = self.config.balance_currency();
         self.account.backtest_id = backtest_id;
+        self.view.account = self.account.clone();
         self.prepare_data().await?;
         // self.account.position.symbol = self.config.symbol.clone().split("_").next().unwrap().to_string();
         self.start_listening(tick_url).await;
@@ -362,18 +812,30 @@ impl ZileanV1 {
         );
         if is_trade {
             let trade = self.trade_cache.pop_front().unwrap_or_default();
+            self.record_message(trade.local_timestamp);
+            for algo in self.algos.iter_mut() {
+                algo.on_trade(&trade);
+            }
+            self.recent_trades.push(trade.clone());
+            self.last_prices.insert(index_key(&trade.symbol), trade.price);
+            self.process_pending_cancels(trade.local_timestamp);
+            self.process_transfers(trade.local_timestamp);
+            let (account, orders) = self.reported_state(trade.local_timestamp);
             let tick_response = TickResponseTrade {
                 trade,
-                account: self.account.clone(),
-                orders: self.order_list.clone(),
+                account,
+                orders,
+                algos: self.algos.clone(),
             };
             self.match_orders();
             return BacktestResponse::normal_response(sonic_rs::to_string(&tick_response).unwrap());
         }
-        if !self.zconfig.use_trade{
-            self.match_orders();
+        if let Some(next) = self.data_cache.front() {
+            self.process_pending_cancels(next.local_timestamp);
         }
+        self.match_orders();
         let mut depth = self.data_cache.pop_front().unwrap_or_default();
+        self.record_message(depth.local_timestamp);
         for ask in depth.asks.iter_mut() {
             ask.1 = (ask.1 * 1e6).round() / 1e6;
         }
@@ -382,165 +844,227 @@ impl ZileanV1 {
         }
         // level 0 changed, trades happened, match orders
         self.depth = depth.clone();
+        self.liquidity.refresh(&self.depth);
         for position in self.account.position.iter_mut() {
             position.1.round();
             // check Forced Liquidation and stop loss
         }
-        self.close_order_check(&self.depth.clone());
+        self.update_marks();
+        self.settle_funding(self.depth.local_timestamp);
+        self.settle_expired_options(self.depth.local_timestamp);
+        self.deliver_expired_futures(self.depth.local_timestamp);
+        self.process_transfers(self.depth.local_timestamp);
+        self.account.mark_to_market(&self.marks);
+        self.run_algos();
+        let (account, orders) = self.reported_state(self.depth.local_timestamp);
         let tick_response = TickResponseDepth {
             depth: self.depth.clone(),
-            account: self.account.clone(),
-            orders: self.order_list.clone(),
+            account,
+            orders,
+            algos: self.algos.clone(),
         };
         BacktestResponse::normal_response(sonic_rs::to_string(&tick_response).unwrap_or_else(|_| "{{}}".to_string()))
     }
 
-    // check for forced liquidation and stop loss conditions
-    fn close_order_check(&mut self, depth: &Depth) {
-        let mut close_list: Vec<Order> = Vec::new();
-
-        // Check Long positions
-        let position_long = self.account.position.get_mut(&(
-            depth.symbol.clone(),
-            PositionSide::Long,
-            depth.exchange,
-        ));
-        let mut total_amount = dec!(0);
-        let mid_price = (depth.asks[0].0 + depth.bids[0].0) / 2.0;
-
-        if let Some(position) = position_long {
-            // Sort the position by stop_loss price (ascending)
-            position
-                .stop_loss
-                .sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-
-            for (index, loss) in position.stop_loss.iter_mut().enumerate() {
-                total_amount += loss.1;
-                if Decimal::from_f64(mid_price).unwrap_or(dec!(0)) < loss.0 {
-                    let stop_loss_order = Order {
-                        contract_type: ContractType::Futures,
-                        symbol: depth.symbol.clone(),
-                        exchange: depth.exchange,
-                        order_type: OrderType::Market,
-                        position_side: PositionSide::Long,
-                        leverage: position.leverage,
-                        cid: "st-".to_string() + depth.symbol.clone().as_str(),
-                        price: 0.0,
-                        amount: loss.1.to_f64().unwrap_or(0.0),
-                        side: OrderSide::Sell,
-                        timestamp: 0,
-                        ..Default::default()
-                    };
-                    close_list.push(stop_loss_order);
-                }
-                if total_amount >= position.amount_total {
-                    // 只清除未遍历的部分
-                    position.stop_loss.drain(index + 1..);
-                    break;
-                }
-            }
-        }
-
-        // Check Short positions
-        let position_short = self.account.position.get_mut(&(
-            depth.symbol.clone(),
-            PositionSide::Short,
-            depth.exchange,
-        ));
-        total_amount = dec!(0);
-
-        if let Some(position) = position_short {
-            // Sort the position by stop_loss price (descending)
-            position
-                .stop_loss
-                .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
-
-            for (index, loss) in position.stop_loss.iter_mut().enumerate() {
-                total_amount += loss.1;
-                if Decimal::from_f64(mid_price).unwrap_or(dec!(0)) > loss.0 {
-                    let stop_loss_order = Order {
-                        contract_type: ContractType::Futures,
-                        symbol: depth.symbol.clone(),
-                        exchange: depth.exchange,
-                        order_type: OrderType::Market,
-                        position_side: PositionSide::Short,
-                        leverage: position.leverage,
-                        cid: "st-".to_string() + depth.symbol.clone().as_str(),
-                        price: 1e6,
-                        amount: loss.1.to_f64().unwrap_or(0.0),
-                        side: OrderSide::Sell,
-                        timestamp: 0,
-                        ..Default::default()
-                    };
-                    close_list.push(stop_loss_order);
-                }
-                if total_amount >= position.amount_total {
-                    // Clear all stop-loss orders
-                    position.stop_loss.drain(index + 1..);
-                    break;
-                }
-            }
-        }
-
-        // Force close all remaining positions with market price if necessary
-
-        // todo: force close, error in spot
-        // for position_side in &[PositionSide::Long, PositionSide::Short] {
-        //     if let Some(position) = self.account.position.get_mut(&(
-        //         depth.symbol.clone(),
-        //         position_side.clone(),
-        //         depth.exchange,
-        //     )) {
-        //         let margin_need = (position.entry_price - depth.asks[0].0) * position.amount_total;
-        //         if (position_side == &PositionSide::Short && margin_need < 0.0
-        //             || position_side == &PositionSide::Long && margin_need > 0.0)
-        //             && position.margin_value - margin_need.abs() < 0.0
-        //         {
-        //             let fc_price = (depth.asks[0].0 + depth.bids[0].0) / 2.0;
-        //             let force_close_order = Order {
-        //                 contract_type: ContractType::Futures,
-        //                 symbol: depth.symbol.clone(),
-        //                 exchange: depth.exchange,
-        //                 order_type: OrderType::Market,
-        //                 position_side: position_side.clone(),
-        //                 leverage: position.leverage,
-        //                 cid: "fc-".to_string() + depth.symbol.clone().as_str(),
-        //                 price: fc_price,
-        //                 amount: position.amount_total,
-        //                 side: OrderSide::Sell,
-        //                 timestamp: 0,
-        //                 ..Default::default()
-        //             };
-        //             close_list.push(force_close_order);
-        //             position.amount_total = 0.0; // Ensure the total is reset
-        //             position.stop_loss.clear(); // Clear stop-loss
-        //         }
-        //     }
-        // }
-
-        // // Process all close orders
-        // for order in close_list {
-        //     self.post_order(order);
-        // }
+    // rules of the instrument, or the default precision when the symbol has no spec
+    fn check_order(&self, order: &Order) -> Result<(), String> {
+        match self.instruments.get(order.exchange, &order.symbol) {
+            // spot and linear amounts are in the base currency, their margin and pnl don't take a contract size
+            Some(spec)
+                if spec.multiplier != 1.0
+                    && matches!(order.contract_type, ContractType::Spot | ContractType::Futures) =>
+            {
+                Err("Invalid instrument, multiplier is only supported for Inverse and Options.".to_string())
+            }
+            Some(spec) if order.price > 0.0 && order.amount > 0.0 => spec.check_order(order),
+            _ => Self::check_price_amount(order.price, order.amount),
+        }
+    }
+
+    // amount of an order made by the engine, down to the step of the instrument
+    fn floor_step(&self, exchange: Exchange, symbol: &str, amount: f64) -> f64 {
+        match self.instruments.get(exchange, symbol) {
+            Some(spec) => spec.floor_amount(amount),
+            None => round6(amount),
+        }
+    }
+
+    // check the precision of price and amount
+    fn check_price_amount(price: f64, amount: f64) -> Result<(), String> {
+        let dec_amount = Decimal::from_f64(amount).unwrap_or(dec!(0));
+        let dec_price = Decimal::from_f64(price).unwrap_or(dec!(0));
+        if (dec_amount - (dec_amount * dec!(1e6)).round() / dec!(1e6)).abs() >= dec!(1e-7) {
+            return Err("Invalid amount, position fix too small.".to_string());
+        }
+        if (dec_price - (dec_price * dec!(1e12)).round() / dec!(1e12)).abs() >= dec!(1e-13) {
+            return Err("Invalid amount, position fix too small.".to_string());
+        }
+        if dec_price <= dec!(0) || dec_amount <= dec!(0) {
+            return Err("Invalid order.".to_string());
+        }
+        Ok(())
+    }
+
+    // orders of the client, in one-way mode buys and sells net into one position per symbol
+    // orders of the client are seen by the GET_ commands once posted, fills and cancels come with the ticks
+    pub fn post_client_order(&mut self, order: Order) -> BacktestResponse {
+        let cid = order.cid.clone();
+        let response = self.submit_order(order);
+        if response.status == BacktestStatus::Ok {
+            let half = self.split_half(&cid);
+            for cid in std::iter::once(cid).chain(half) {
+                let Some(order) = self.order_list.inner.iter().find(|x| x.cid == cid).cloned() else {
+                    continue;
+                };
+                self.view.orders.remove_order(cid);
+                self.view.orders.insert_order(order);
+            }
+        }
+        response
+    }
+
+    pub fn submit_order(&mut self, mut order: Order) -> BacktestResponse {
+        if self.config.position_mode == PositionMode::Hedge || order.contract_type == ContractType::Spot {
+            return self.post_order(order);
+        }
+        if order.stop_price.is_some() {
+            // netted against the position when triggered
+            order.one_way = true;
+            order.position_side = PositionSide::Long;
+            return self.post_order(order);
+        }
+        self.post_one_way(order)
+    }
+
+    // side is the direction: close the opposite position first, open the rest on the other side
+    fn post_one_way(&mut self, order: Order) -> BacktestResponse {
+        let buy = order.side == OrderSide::Buy;
+        let (opposite, same) = if buy {
+            (PositionSide::Short, PositionSide::Long)
+        } else {
+            (PositionSide::Long, PositionSide::Short)
+        };
+        // one signed position per symbol, the order nets against the whole opposite position
+        let (total, available) = self
+            .account
+            .position
+            .get(&(order.symbol.clone(), opposite.clone(), order.exchange))
+            .map_or((0.0, 0.0), |x| {
+                (x.amount_total.to_f64().unwrap_or(0.0), x.amount_available.to_f64().unwrap_or(0.0))
+            });
+        let close_amount = self.floor_step(order.exchange, &order.symbol, total.min(order.amount));
+        let open_amount = if order.reduce_only {
+            0.0
+        } else {
+            self.floor_step(order.exchange, &order.symbol, order.amount - close_amount)
+        };
+        if close_amount <= 0.0 && open_amount <= 0.0 {
+            return BacktestResponse::bad_request("ReduceOnly Order is rejected.".to_string());
+        }
+        if close_amount > round6(available) {
+            return BacktestResponse::bad_request("Insufficient amount, the position is freezed by open orders.".to_string());
+        }
+        // both halves are posted or none
+        let snapshot = (close_amount > 0.0 && open_amount > 0.0)
+            .then(|| (self.account.clone(), self.order_list.clone(), self.rng.clone()));
+        let mut messages = vec![];
+        if close_amount > 0.0 {
+            let close = Order {
+                side: OrderSide::Sell,
+                position_side: opposite,
+                amount: close_amount,
+                ..order.clone()
+            };
+            let response = self.post_order(close);
+            if response.status != BacktestStatus::Ok {
+                return response;
+            }
+            messages.push(response.message);
+        }
+        if open_amount > 0.0 {
+            // through zero, the open part gets its own cid
+            let (cid, split_of) = if close_amount > 0.0 {
+                (format!("{}-open", order.cid), Some(order.cid.clone()))
+            } else {
+                (order.cid.clone(), None)
+            };
+            let open = Order {
+                cid,
+                side: OrderSide::Buy,
+                position_side: same,
+                amount: open_amount,
+                split_of,
+                ..order
+            };
+            let response = self.post_order(open);
+            if response.status != BacktestStatus::Ok {
+                if let Some(snapshot) = snapshot {
+                    (self.account, self.order_list, self.rng) = snapshot;
+                }
+                return response;
+            }
+            messages.push(response.message);
+        }
+        BacktestResponse::normal_response(messages.join(" "))
     }
 
     // return cid when success
     pub fn post_order(&mut self, mut order: Order) -> BacktestResponse {
+        if order.contract_type != ContractType::Spot {
+            let key = (order.symbol.clone(), order.position_side.clone(), order.exchange);
+            if let Some(leverage) = self.leverages.get(&key) {
+                order.leverage = *leverage;
+            }
+        }
         // check account balance, fix the amount and price
+        if let Err(e) = self.check_order(&order) {
+            return BacktestResponse::bad_request(e);
+        }
+        if let Some(display_amount) = order.display_amount {
+            if Self::check_price_amount(order.price, display_amount).is_err()
+                || display_amount > order.amount
+            {
+                return BacktestResponse::bad_request("Invalid display amount.".to_string());
+            }
+        }
         let dec_amount = Decimal::from_f64(order.amount).unwrap_or(dec!(0));
         let dec_price = Decimal::from_f64(order.price).unwrap_or(dec!(0));
-        if (dec_amount - (dec_amount * dec!(1e6)).round() / dec!(1e6)).abs() >= dec!(1e-7) {
-            return BacktestResponse::bad_request(
-                "Invalid amount, position fix too small.".to_string(),
-            );
+        order.multiplier = self
+            .instruments
+            .get(order.exchange, &order.symbol)
+            .map_or(1.0, |spec| spec.multiplier);
+        let expiry = self.instruments.get(order.exchange, &order.symbol).and_then(|spec| spec.expiry);
+        if order.contract_type != ContractType::Options
+            && (expiry.is_some_and(|x| x <= self.depth.local_timestamp)
+                || self.expired.contains(&(order.exchange, order.symbol.clone())))
+        {
+            return BacktestResponse::bad_request("Contract expired.".to_string());
         }
-        if (dec_price - (dec_price * dec!(1e12)).round() / dec!(1e12)).abs() >= dec!(1e-13) {
-            return BacktestResponse::bad_request(
-                "Invalid amount, position fix too small.".to_string(),
-            );
+        if order.contract_type == ContractType::Options {
+            let Some(option) = self
+                .instruments
+                .get(order.exchange, &order.symbol)
+                .and_then(|spec| spec.option.as_ref())
+            else {
+                return BacktestResponse::bad_request("Unknown option instrument.".to_string());
+            };
+            if option.expiry <= self.depth.local_timestamp
+                || self.expired.contains(&(order.exchange, order.symbol.clone()))
+            {
+                return BacktestResponse::bad_request("Option expired.".to_string());
+            }
+            order.option_margin = option.margin_rate * option.strike * order.multiplier;
+            order.leverage = 1;
         }
-        if dec_price <= dec!(0) || dec_amount <= dec!(0) {
-            return BacktestResponse::bad_request("Invalid order.".to_string());
+        // stop orders freeze nothing until triggered, they are posted again by trigger_stop_orders
+        if let Some(stop_price) = order.stop_price {
+            if stop_price <= 0.0 {
+                return BacktestResponse::bad_request("Invalid stop price.".to_string());
+            }
+            let cid = order.cid.clone();
+            let order = self.order_with_latency(order);
+            self.order_list.insert_order(order);
+            return BacktestResponse::normal_response(format!("cid: {} stop order posted.", cid));
         }
         // TODO: change front amount
         // order.amount = (dec_amount * dec!(1e6)).round() / dec!(1e6);
@@ -549,30 +1073,26 @@ impl ZileanV1 {
         let post_value = order.price * amount;
         // check margin for Spot
         if order.contract_type == ContractType::Spot {
-            // Get the position for the symbol or insert a new one if it doesn't exist
-            let position = self
-                .account
-                .position
-                .entry((
-                    order.symbol.clone(),
-                    order.position_side.clone(),
-                    order.exchange,
-                ))
-                .or_default();
-
+            // sells freeze the base currency, buys the quote currency
             match order.side {
                 OrderSide::Sell => {
-                    if position.amount_available < dec_amount {
+                    let holding = self.account.holding(&order.symbol);
+                    if holding.get_available() < dec_amount {
                         return BacktestResponse::bad_request("Insufficient amount.".to_string());
                     }
-                    position.add_freezed(dec_amount);
+                    holding.add_freezed(dec_amount);
                 }
                 OrderSide::Buy => {
-                    self.account.balance.add_freezed(Decimal::from_f64(post_value).unwrap_or(dec!(0)));
+                    let dec_post_value = Decimal::from_f64(post_value).unwrap_or(dec!(0));
+                    let balance = self.account.margin_balance(&order.contract_type, &order.symbol);
+                    if balance.get_available() < dec_post_value {
+                        return BacktestResponse::bad_request("Insufficient balance.".to_string());
+                    }
+                    balance.add_freezed(dec_post_value);
                 }
             }
-        } else if order.contract_type == ContractType::Futures {
-            // check margin for Perpetual
+        } else {
+            // check margin for Perpetual, in coin for inverse contracts
             let position = self
                 .account
                 .position
@@ -612,7 +1132,7 @@ impl ZileanV1 {
                                 if amount_canceled + (order_inn.amount - order_inn.filled_amount)
                                     >= order.amount
                                 {
-                                    self.cancel_order(order_inn.cid.clone());
+                                    self.cancel_order_now(order_inn.cid.clone());
                                     order_inn.amount = (order_inn.amount - order_inn.filled_amount)
                                         + amount_canceled
                                         - order.amount;
@@ -622,7 +1142,7 @@ impl ZileanV1 {
                                     break;
                                 }
                                 amount_canceled += order_inn.amount - order_inn.filled_amount;
-                                self.cancel_order(order_inn.cid.clone());
+                                self.cancel_order_now(order_inn.cid.clone());
                             }
                         }
                         return BacktestResponse::bad_request("Insufficient amount, Canceled the amount out of position automatically.".to_string());
@@ -631,10 +1151,11 @@ impl ZileanV1 {
                     order.open_price = Some(position.entry_price.to_f64().unwrap_or(0.0));
                 }
                 OrderSide::Buy => {
-                    let margin_value_need =
-                        round6(order.amount / order.leverage as f64 * order.price);
+                    let margin_value_need = order.margin_needed(order.price, order.amount);
                     let dec_margin_value_need = Decimal::from_f64(margin_value_need).unwrap_or(dec!(0));
-                    if dec_margin_value_need > self.account.balance.get_available() {
+                    position.leverage = order.leverage;
+                    let balance = self.account.margin_balance(&order.contract_type, &order.symbol);
+                    if dec_margin_value_need > balance.get_available() {
                         return BacktestResponse::bad_request("Insufficient margin.".to_string());
                     }
                     if let Some(loss) = order.stop_loss {
@@ -646,17 +1167,6 @@ impl ZileanV1 {
                                 "Stop loss invailed.".to_string(),
                             );
                         }
-                        let mut found = false;
-                        for loss_list in position.stop_loss.iter_mut() {
-                            if loss_list.0 == dec_loss {
-                                loss_list.1 += dec_amount;
-                                found = true;
-                                break;
-                            }
-                        }
-                        if !found {
-                            position.stop_loss.push((dec_loss, dec_amount));
-                        }
                     }
                     if let Some(profit) = order.take_profit {
                         let dec_profit = Decimal::from_f64(profit).unwrap_or(dec!(0));
@@ -668,9 +1178,8 @@ impl ZileanV1 {
                             );
                         }
                     }
-                    position.leverage = order.leverage;
                     if dec_margin_value_need > dec!(0) {
-                        self.account.balance.add_freezed(dec_margin_value_need);
+                        balance.add_freezed(dec_margin_value_need);
                         order.margin = margin_value_need;
                     }
                 }
@@ -678,102 +1187,1281 @@ impl ZileanV1 {
         }
         // update account
         let cid = order.cid.clone();
-        self.order_list
-            .insert_order(self.latency.order_with_latency(order));
+        let order = self.order_with_latency(order);
+        self.order_list.insert_order(order);
 
         BacktestResponse::normal_response(format!("cid: {} order posted.", cid))
     }
 
-    pub fn cancel_order(&mut self, cid: String) -> BacktestResponse {
-        // remove order from order_list
-        let order = self.order_list.remove_order(cid);
-        if let Some(mut order) = order {
-            if order.state == OrderState::Filled || order.state == OrderState::Canceled {
-                self.order_list.insert_order(order);
-                return BacktestResponse::normal_response(
-                    "Order already filled or Canceled.".to_string(),
-                );
+    // the order was sent on the market data the client saw, so it is late by the feed latency too
+    fn order_with_latency(&mut self, order: Order) -> Order {
+        let rate = self.message_rate();
+        let order = self.latency.order_with_latency(order, rate, &mut self.rng);
+        Order {
+            timestamp: order.timestamp + self.config.feed_latency.latency(order.timestamp, rate, &mut self.rng),
+            ..order
+        }
+    }
+
+    // funding of the open futures positions at each funding time, at the mark price
+    fn settle_funding(&mut self, timestamp: i64) {
+        let Some(funding) = self.config.funding.clone() else {
+            return;
+        };
+        if funding.interval <= 0 {
+            return;
+        }
+        if self.next_funding == 0 {
+            self.next_funding = (timestamp / funding.interval + 1) * funding.interval;
+        }
+        let rate = Decimal::from_f64(funding.rate).unwrap_or(dec!(0));
+        while timestamp >= self.next_funding {
+            self.next_funding += funding.interval;
+            let mut payments = vec![];
+            for ((symbol, side, exchange), position) in self.account.position.iter_mut() {
+                let Some(mark) = self.marks.get(&(*exchange, index_key(symbol))).and_then(|x| Decimal::from_f64(*x)) else {
+                    continue;
+                };
+                if position.amount_total == dec!(0)
+                    || position.contract_type == ContractType::Spot
+                    || position.contract_type == ContractType::Options
+                    || self.instruments.get(*exchange, symbol).is_some_and(|x| x.expiry.is_some())
+                {
+                    continue;
+                }
+                // longs pay a positive rate
+                let mut payment = (position.notional(mark) * rate).round_dp(12);
+                if *side == PositionSide::Short {
+                    payment = -payment;
+                }
+                position.funding -= payment;
+                payments.push((position.contract_type.clone(), symbol.clone(), payment));
             }
-            let mut amount = order.amount - order.filled_amount;
-            let value = order.price * amount;
-            amount = (amount * 1e6).round() / 1e6;
-            if order.side == OrderSide::Buy {
-                self.account
-                    .balance
-                    .sub_freezed(Decimal::from_f64(value / order.leverage as f64).unwrap_or(dec!(0)));
-            } else {
-                self.account
-                    .position
-                    .entry((
-                        order.symbol.clone(),
-                        order.position_side.clone(),
-                        order.exchange,
-                    ))
-                    .or_default()
-                    .sub_freezed(Decimal::from_f64(amount).unwrap_or(dec!(0)));
+            for (contract_type, symbol, payment) in payments {
+                self.account.margin_balance(&contract_type, &symbol).charge(payment);
             }
-            order.state = OrderState::Canceled;
-            order.timestamp = self.depth.local_timestamp;
-            let cid = order.cid.clone();
-            // return an ok status response
-            self.order_list.insert_order(order);
-            BacktestResponse::normal_response(format!("cid: {} order canceled.", cid))
-        } else {
-            // cancel order not found
-            // return an error status response
-            BacktestResponse::bad_request("Order not found".to_string())
         }
     }
 
-    fn match_orders(&mut self) {
-        let depth = &mut self.depth.clone();
-        // when tick update, try to match orders
-        let filled_stack = self
-            .order_list
-            .execute_orders(depth, self.fill_model.clone());
-        if !filled_stack.is_empty() {
-            debug!("{:?}", filled_stack);
+    // the mid of the depth marks its own symbol only, options and futures are not marked by their underlying
+    fn update_marks(&mut self) {
+        if let Some((ask, bid)) = self.depth.asks.first().zip(self.depth.bids.first()) {
+            self.marks.insert((self.depth.exchange, index_key(&self.depth.symbol)), (ask.0 + bid.0) / 2.0);
+            self.indexes.insert(index_key(&self.depth.symbol), (ask.0 + bid.0) / 2.0);
         }
-        for filled in filled_stack {
-            let account = self
-                .account
-                .position
-                .entry((filled.symbol.clone(), filled.side.clone(), filled.exchange))
-                .or_default();
-            self.account.balance.fill_freezed(&filled);
-            account.update_pos(&filled);
-            // info!("current first orders: {:?}", self.order_list.inner);
-            // if order too much, warning!
-            if self.order_list.inner.len() > 1000 {
-                log::warn!(
-                    "Order list too long: {}, consider to cancel some orders.",
-                    self.order_list.inner.len()
-                );
-            }
-            // take profit
-            if filled.take_profit.is_some()
-                && ((filled.filled_amount > dec!(0) && filled.side == PositionSide::Long)
-                    || (filled.filled_amount < dec!(0) && filled.side == PositionSide::Short))
-            {
-                let take_profit_order = Order {
-                    cid: format!("tp-{}", filled.cid),
-                    exchange: filled.exchange,
-                    symbol: filled.symbol.clone(),
-                    position_side: filled.side.clone(),
-                    contract_type: filled.contract_type.clone(),
+    }
+
+    // market data messages in the last second
+    fn message_rate(&self) -> f64 {
+        self.message_times.len() as f64
+    }
+
+    fn record_message(&mut self, timestamp: i64) {
+        self.message_times.push_back(timestamp);
+        while self.message_times.front().is_some_and(|x| *x + 1_000_000 <= timestamp) {
+            self.message_times.pop_front();
+        }
+    }
+
+    // cancel requested by the client, it reaches the exchange after the feed and cancel latency
+    pub fn cancel_order(&mut self, cid: String) -> BacktestResponse {
+        // the open half of a one-way order through zero goes with it
+        if let Some(half) = self.split_half(&cid) {
+            self.cancel_order(half);
+        }
+        if self.config.cancel_latency.is_none() && self.config.feed_latency.is_none() {
+            return self.cancel_order_now(cid);
+        }
+        if self.order_list.get_open_mut(&cid).is_none() {
+            return self.cancel_order_now(cid);
+        }
+        let (now, rate) = (self.depth.local_timestamp, self.message_rate());
+        let timestamp = now
+            + self.config.feed_latency.latency(now, rate, &mut self.rng)
+            + self.config.cancel_latency.latency(now, rate, &mut self.rng);
+        self.pending_cancels.push((timestamp, cid.clone()));
+        BacktestResponse::normal_response(format!("cid: {} cancel pending.", cid))
+    }
+
+    // operations applied in order, all of them are rolled back when one fails
+    pub fn batch(&mut self, ops: Vec<BatchOp>) -> BacktestResponse {
+        match self.apply_batch(ops) {
+            Ok(responses) => BacktestResponse::normal_response(
+                sonic_rs::to_string(&responses).unwrap_or_else(|_| "[]".to_string()),
+            ),
+            Err(responses) => BacktestResponse::bad_request(
+                sonic_rs::to_string(&responses).unwrap_or_else(|_| "[]".to_string()),
+            ),
+        }
+    }
+
+    // responses of the ops, up to the failed one when they are rolled back
+    fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<Vec<BacktestResponse>, Vec<BacktestResponse>> {
+        let snapshot = (
+            self.account.clone(),
+            self.order_list.clone(),
+            self.pending_cancels.clone(),
+            self.pending_amends.clone(),
+            self.view.orders.clone(),
+            self.rng.clone(),
+        );
+        let mut responses = Vec::with_capacity(ops.len());
+        for op in ops {
+            let response = match op {
+                BatchOp::PostOrder(order) => self.post_client_order(*order),
+                BatchOp::CancelOrder(cid) => self.cancel_order(cid),
+                BatchOp::AmendOrder(amend) => self.amend_order(amend),
+            };
+            let failed = response.status != BacktestStatus::Ok;
+            responses.push(response);
+            if failed {
+                (
+                    self.account,
+                    self.order_list,
+                    self.pending_cancels,
+                    self.pending_amends,
+                    self.view.orders,
+                    self.rng,
+                ) = snapshot;
+                return Err(responses);
+            }
+        }
+        Ok(responses)
+    }
+
+    // the ops of a batch with the next tick, as BATCH then TICK in one request
+    pub fn step(&mut self, ops: Vec<BatchOp>, tick: &str) -> BacktestResponse {
+        match self.apply_batch(ops) {
+            Ok(results) => {
+                self.deliver_tick();
+                // the tick is a message when the backtest is over
+                let tick = sonic_rs::from_str::<sonic_rs::Value>(tick).unwrap_or_else(|_| sonic_rs::Value::from(tick));
+                let response = StepResponse { results, tick };
+                BacktestResponse::normal_response(sonic_rs::to_string(&response).unwrap_or_default())
+            }
+            Err(results) => BacktestResponse::bad_request(
+                sonic_rs::to_string(&results).unwrap_or_else(|_| "[]".to_string()),
+            ),
+        }
+    }
+
+    // the tick computed ahead is delivered, its state is the one the GET_ commands see
+    pub fn deliver_tick(&mut self) {
+        if let Some(view) = self.next_view.take() {
+            self.view = view;
+        }
+    }
+
+    // an order by cid, filled and canceled ones stay a few seconds in the list
+    pub fn get_order(&self, cid: String) -> BacktestResponse {
+        match self.view.orders.inner.iter().find(|x| x.cid == cid) {
+            Some(order) => BacktestResponse::normal_response(sonic_rs::to_string(order).unwrap_or_default()),
+            None => BacktestResponse::bad_request("Order not found".to_string()),
+        }
+    }
+
+    pub fn get_open_orders(&self) -> BacktestResponse {
+        let orders: Vec<&Order> = self
+            .view
+            .orders
+            .inner
+            .iter()
+            .filter(|x| x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+            .collect();
+        BacktestResponse::normal_response(sonic_rs::to_string(&orders).unwrap_or_else(|_| "[]".to_string()))
+    }
+
+    // the account of the last delivered tick, after its ack latency
+    pub fn get_account(&self) -> BacktestResponse {
+        BacktestResponse::normal_response(sonic_rs::to_string(&self.view.account).unwrap_or_default())
+    }
+
+    // the logged fills the client has seen with the last delivered tick
+    pub fn get_fills(&self, query: FillQuery) -> BacktestResponse {
+        let visible = self.view.fills.saturating_sub(self.fill_count - self.fills.len());
+        let fills: Vec<&FilledStack> = self.fills.iter().take(visible).filter(|x| query.matches(x)).collect();
+        BacktestResponse::normal_response(sonic_rs::to_string(&fills).unwrap_or_else(|_| "[]".to_string()))
+    }
+
+    // one request for every open order matching the filter, the cancels share one latency
+    pub fn cancel_all(&mut self, filter: CancelAll) -> BacktestResponse {
+        let cids: Vec<String> = self
+            .order_list
+            .inner
+            .iter()
+            .filter(|x| {
+                (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled) && filter.matches(x)
+            })
+            .map(|x| x.cid.clone())
+            .collect();
+        let halves: Vec<String> = cids
+            .iter()
+            .filter_map(|cid| self.split_half(cid))
+            .filter(|half| !cids.contains(half))
+            .collect();
+        let mut cids = cids;
+        cids.extend(halves);
+        if self.config.cancel_latency.is_none() && self.config.feed_latency.is_none() {
+            for cid in cids.iter() {
+                self.cancel_order_now(cid.clone());
+            }
+        } else {
+            let (now, rate) = (self.depth.local_timestamp, self.message_rate());
+            let timestamp = now
+                + self.config.feed_latency.latency(now, rate, &mut self.rng)
+                + self.config.cancel_latency.latency(now, rate, &mut self.rng);
+            self.pending_cancels.extend(cids.iter().map(|cid| (timestamp, cid.clone())));
+        }
+        BacktestResponse::normal_response(sonic_rs::to_string(&cids).unwrap_or_else(|_| "[]".to_string()))
+    }
+
+    // cancels and amends whose latency has passed, fills before them win the race
+    fn process_pending_cancels(&mut self, timestamp: i64) {
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .pending_amends
+            .drain(..)
+            .partition(|(amend_time, _)| *amend_time <= timestamp);
+        self.pending_amends = pending;
+        for (amend_time, amend) in due {
+            let Some(filled) = self.order_list.get_open_mut(&amend.cid).map(|x| x.filled_amount) else {
+                continue;
+            };
+            // a size down the fills got to first leaves the filled part only
+            if self.split_half(&amend.cid).is_none() && amend.amount.is_some_and(|x| x <= filled) {
+                self.cancel_order_now(amend.cid);
+                continue;
+            }
+            let cid = amend.cid.clone();
+            let response = self.amend_now(amend, amend_time);
+            if response.status != BacktestStatus::Ok {
+                log::warn!("amend of {} not applied: {}", cid, response.message);
+            }
+        }
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .pending_cancels
+            .drain(..)
+            .partition(|(cancel_time, _)| *cancel_time <= timestamp);
+        self.pending_cancels = pending;
+        for (_, cid) in due {
+            self.cancel_order_now(cid);
+        }
+    }
+
+    // state shown to the client, order and account changes are reported after the ack latency
+    // the state is kept for the GET_ commands until the tick is delivered
+    fn reported_state(&mut self, timestamp: i64) -> (Account, OrderList) {
+        let current = ClientView {
+            account: self.account.clone(),
+            orders: self.order_list.clone(),
+            fills: self.fill_count,
+            ..Default::default()
+        };
+        let view = if self.config.ack_latency.is_none() {
+            current
+        } else {
+            let last = self.reports.back().map_or(i64::MIN, |x| x.0);
+            let rate = self.message_rate();
+            let visible_at = (timestamp + self.config.ack_latency.latency(timestamp, rate, &mut self.rng)).max(last);
+            self.reports.push_back((visible_at, current));
+            // keep the latest visible report and the ones still in flight
+            while self.reports.len() > 1 && self.reports[1].0 <= timestamp {
+                self.reports.pop_front();
+            }
+            match self.reports.front() {
+                Some((visible_at, view)) if *visible_at <= timestamp => view.clone(),
+                _ => ClientView {
+                    account: Account {
+                        backtest_id: self.account.backtest_id.clone(),
+                        balance: self.config.balance.clone(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            }
+        };
+        let reported = (view.account.clone(), view.orders.clone());
+        self.next_view = Some(ClientView { depth: self.depth.clone(), ..view });
+        reported
+    }
+
+    // fills of GET_FILLS, the oldest are dropped past FILL_LOG_CAP
+    fn log_fill(&mut self, filled: &FilledStack) {
+        if self.fills.len() == FILL_LOG_CAP {
+            self.fills.pop_front();
+        }
+        self.fills.push_back(filled.clone());
+        self.fill_count += 1;
+    }
+
+    pub fn cancel_order_now(&mut self, cid: String) -> BacktestResponse {
+        // remove order from order_list
+        let order = self.order_list.remove_order(cid);
+        if let Some(mut order) = order {
+            if order.state == OrderState::Filled || order.state == OrderState::Canceled {
+                self.order_list.insert_order(order);
+                return BacktestResponse::normal_response(
+                    "Order already filled or Canceled.".to_string(),
+                );
+            }
+            let mut amount = order.amount - order.filled_amount;
+            let value = match order.contract_type {
+                ContractType::Inverse | ContractType::Options => order.margin_needed(order.price, amount),
+                _ => order.price * amount / order.leverage as f64,
+            };
+            amount = (amount * 1e6).round() / 1e6;
+            if order.stop_price.is_some() {
+                // untriggered stop order, nothing freezed
+            } else if order.side == OrderSide::Buy {
+                self.account
+                    .margin_balance(&order.contract_type, &order.symbol)
+                    .sub_freezed(Decimal::from_f64(value).unwrap_or(dec!(0)));
+            } else if order.contract_type == ContractType::Spot {
+                self.account
+                    .holding(&order.symbol)
+                    .sub_freezed(Decimal::from_f64(amount).unwrap_or(dec!(0)));
+            } else {
+                self.account
+                    .position
+                    .entry((
+                        order.symbol.clone(),
+                        order.position_side.clone(),
+                        order.exchange,
+                    ))
+                    .or_default()
+                    .sub_freezed(Decimal::from_f64(amount).unwrap_or(dec!(0)));
+            }
+            order.state = OrderState::Canceled;
+            order.timestamp = self.depth.local_timestamp;
+            let cid = order.cid.clone();
+            // return an ok status response
+            self.order_list.insert_order(order);
+            BacktestResponse::normal_response(format!("cid: {} order canceled.", cid))
+        } else {
+            // cancel order not found
+            // return an error status response
+            BacktestResponse::bad_request("Order not found".to_string())
+        }
+    }
+
+    // open half of a one-way order through zero
+    fn split_half(&self, cid: &str) -> Option<String> {
+        self.order_list
+            .inner
+            .iter()
+            .find(|x| {
+                x.split_of.as_deref() == Some(cid)
+                    && (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+            })
+            .map(|x| x.cid.clone())
+    }
+
+    // amends reach the exchange after the feed and order latency, a size down after the cancel latency
+    pub fn amend_order(&mut self, amend: AmendOrder) -> BacktestResponse {
+        let Some(order) = self.order_list.get_open_mut(&amend.cid).cloned() else {
+            return BacktestResponse::bad_request("Order not found".to_string());
+        };
+        let size_down = amend.price.is_none_or(|x| x == order.price) && amend.amount.is_some_and(|x| x < order.amount);
+        let entry_latency = if size_down { &self.config.cancel_latency } else { &self.latency };
+        if entry_latency.is_none() && self.config.feed_latency.is_none() {
+            return self.amend_now(amend, self.depth.local_timestamp);
+        }
+        let price = amend.price.unwrap_or(order.price);
+        let amount = amend.amount.unwrap_or(order.amount);
+        if self.split_half(&amend.cid).is_none() {
+            if let Err(e) = self.check_order(&Order { price, amount, ..order.clone() }) {
+                return BacktestResponse::bad_request(e);
+            }
+            if amount <= order.filled_amount {
+                return BacktestResponse::bad_request("Invalid amount, less than filled amount.".to_string());
+            }
+        } else if amend.amount.is_some_and(|x| round6(x - order.amount) <= 0.0) {
+            return BacktestResponse::bad_request("Invalid amount, not over the closing part.".to_string());
+        }
+        let (now, rate) = (self.depth.local_timestamp, self.message_rate());
+        let entry_latency = entry_latency.clone();
+        let timestamp = now
+            + entry_latency.latency(now, rate, &mut self.rng)
+            + self.config.feed_latency.latency(now, rate, &mut self.rng);
+        let cid = amend.cid.clone();
+        self.pending_amends.push((timestamp, amend));
+        BacktestResponse::normal_response(format!("cid: {} amend pending.", cid))
+    }
+
+    // the price of a one-way order through zero moves both halves, its amount is of both and changes the open half
+    fn amend_now(&mut self, amend: AmendOrder, timestamp: i64) -> BacktestResponse {
+        let Some(half) = self.split_half(&amend.cid) else {
+            return self.amend_one(amend, timestamp);
+        };
+        let Some(close_amount) = self.order_list.get_open_mut(&amend.cid).map(|x| x.amount) else {
+            return self.amend_one(amend, timestamp);
+        };
+        let open_amount = match amend.amount {
+            Some(amount) if round6(amount - close_amount) <= 0.0 => {
+                return BacktestResponse::bad_request("Invalid amount, not over the closing part.".to_string());
+            }
+            amount => amount.map(|x| round6(x - close_amount)),
+        };
+        let snapshot = (self.account.clone(), self.order_list.clone(), self.rng.clone());
+        let response = self.amend_one(AmendOrder { cid: half, price: amend.price, amount: open_amount }, timestamp);
+        if response.status != BacktestStatus::Ok {
+            return response;
+        }
+        if amend.price.is_some() {
+            let response = self.amend_one(AmendOrder { cid: amend.cid.clone(), price: amend.price, amount: None }, timestamp);
+            if response.status != BacktestStatus::Ok {
+                (self.account, self.order_list, self.rng) = snapshot;
+                return response;
+            }
+        }
+        BacktestResponse::normal_response(format!("cid: {} order amended.", amend.cid))
+    }
+
+    // change price and/or amount of an open order, queue position is kept when only the size goes down
+    fn amend_one(&mut self, amend: AmendOrder, timestamp: i64) -> BacktestResponse {
+        let Some(order) = self.order_list.get_open_mut(&amend.cid).cloned() else {
+            return BacktestResponse::bad_request("Order not found".to_string());
+        };
+        let price = amend.price.unwrap_or(order.price);
+        let amount = amend.amount.unwrap_or(order.amount);
+        if let Err(e) = self.check_order(&Order { price, amount, ..order.clone() }) {
+            return BacktestResponse::bad_request(e);
+        }
+        if amount <= order.filled_amount {
+            return BacktestResponse::bad_request(
+                "Invalid amount, less than filled amount.".to_string(),
+            );
+        }
+        let rest_old = order.amount - order.filled_amount;
+        let rest_new = amount - order.filled_amount;
+
+        // adjust freezed balance or position, untriggered stop orders freeze nothing
+        if order.stop_price.is_none() {
+            if order.side == OrderSide::Buy {
+                let freezed = |price: f64, rest: f64| match order.contract_type {
+                    ContractType::Inverse | ContractType::Options => order.margin_needed(price, rest),
+                    _ => price * rest / order.leverage as f64,
+                };
+                let freezed_old = Decimal::from_f64(freezed(order.price, rest_old)).unwrap_or(dec!(0));
+                let freezed_new = Decimal::from_f64(freezed(price, rest_new)).unwrap_or(dec!(0));
+                let delta = freezed_new - freezed_old;
+                let balance = self.account.margin_balance(&order.contract_type, &order.symbol);
+                if delta > balance.get_available() {
+                    return BacktestResponse::bad_request("Insufficient margin.".to_string());
+                }
+                if delta > dec!(0) {
+                    balance.add_freezed(delta);
+                } else {
+                    balance.sub_freezed(-delta);
+                }
+            } else if order.contract_type == ContractType::Spot {
+                let holding = self.account.holding(&order.symbol);
+                let delta = Decimal::from_f64(round6(rest_new - rest_old)).unwrap_or(dec!(0));
+                if delta > holding.get_available() {
+                    return BacktestResponse::bad_request("Insufficient amount.".to_string());
+                }
+                if delta > dec!(0) {
+                    holding.add_freezed(delta);
+                } else {
+                    holding.sub_freezed(-delta);
+                }
+            } else {
+                let position = self
+                    .account
+                    .position
+                    .entry((
+                        order.symbol.clone(),
+                        order.position_side.clone(),
+                        order.exchange,
+                    ))
+                    .or_default();
+                let delta = Decimal::from_f64(round6(rest_new - rest_old)).unwrap_or(dec!(0));
+                if delta > position.amount_available {
+                    return BacktestResponse::bad_request("Insufficient amount.".to_string());
+                }
+                if delta > dec!(0) {
+                    position.add_freezed(delta);
+                } else {
+                    position.sub_freezed(-delta);
+                }
+            }
+        }
+
+        // a price change or a size increase loses the queue position
+        let requeue = price != order.price || amount > order.amount;
+        if let Some(order) = self.order_list.get_open_mut(&amend.cid) {
+            if order.contract_type != ContractType::Spot && order.side == OrderSide::Buy {
+                order.margin = order.margin_needed(price, amount);
+            }
+            order.price = price;
+            order.amount = amount;
+            if requeue {
+                order.front_amount = -1.0;
+                order.timestamp = timestamp;
+            }
+        }
+        BacktestResponse::normal_response(format!("cid: {} order amended.", amend.cid))
+    }
+
+    // leverage of one position, the open orders and the position margin are freezed again at the new leverage
+    pub fn set_leverage(&mut self, request: SetLeverage) -> BacktestResponse {
+        let key = (request.symbol.clone(), request.position_side.clone(), request.exchange);
+        let contract_type = match self.request_contract_type(&request.contract_type, &key) {
+            Ok(contract_type) => contract_type,
+            Err(e) => return BacktestResponse::bad_request(e),
+        };
+        if contract_type == ContractType::Spot || contract_type == ContractType::Options {
+            return BacktestResponse::bad_request(format!(
+                "Leverage is not supported for {:?}.",
+                contract_type
+            ));
+        }
+        if request.leverage == 0 {
+            return BacktestResponse::bad_request("Invalid leverage.".to_string());
+        }
+        if let Some(spec) = self.instruments.get(request.exchange, &request.symbol) {
+            if spec.max_leverage > 0 && request.leverage > spec.max_leverage {
+                return BacktestResponse::bad_request(Reject::Leverage.message(
+                    request.exchange,
+                    spec,
+                    request.leverage,
+                ));
+            }
+        }
+        let leverage = Decimal::from_u32(request.leverage).unwrap_or(dec!(1));
+
+        // margin the change gives back, negative when it needs more
+        let mut released = dec!(0);
+        let mut freezes = vec![];
+        for order in self.order_list.inner.iter() {
+            if order.symbol != request.symbol
+                || order.position_side != request.position_side
+                || order.exchange != request.exchange
+                || order.contract_type != contract_type
+                || order.side != OrderSide::Buy
+                || order.stop_price.is_some()
+                || !(order.state == OrderState::Open || order.state == OrderState::PartiallyFilled)
+            {
+                continue;
+            }
+            let rest = order.amount - order.filled_amount;
+            let freezed = |order: &Order| match order.contract_type {
+                ContractType::Inverse => order.margin_needed(order.price, rest),
+                _ => order.price * rest / order.leverage as f64,
+            };
+            let freezed_old = Decimal::from_f64(freezed(order)).unwrap_or(dec!(0));
+            let freezed_new = freezed(&Order { leverage: request.leverage, ..order.clone() });
+            let freezed_new = Decimal::from_f64(freezed_new).unwrap_or(dec!(0));
+            freezes.push((freezed_old, freezed_new));
+            released += freezed_old - freezed_new;
+        }
+        let margin_new = self.account.position.get(&key).map(|position| {
+            if position.amount_total == dec!(0) || position.entry_price == dec!(0) {
+                dec!(0)
+            } else if contract_type == ContractType::Inverse {
+                (position.amount_total.abs() * position.multiplier / position.entry_price / leverage).round_dp(12)
+            } else {
+                position.amount_total.abs() * position.entry_price / leverage
+            }
+        });
+        // the added margin stays with the position
+        if let Some(margin_new) = margin_new {
+            let position = &self.account.position[&key];
+            released += position.margin_value.abs() - position.added_margin - margin_new;
+        }
+
+        let balance = self.account.margin_balance(&contract_type, &request.symbol);
+        if -released > balance.get_available() {
+            return BacktestResponse::bad_request("Insufficient margin.".to_string());
+        }
+        for (freezed_old, freezed_new) in freezes {
+            balance.sub_freezed(freezed_old);
+            balance.add_freezed(freezed_new);
+        }
+        if let Some(margin_new) = margin_new {
+            let position = self.account.position.get_mut(&key).unwrap();
+            let margin_old = position.margin_value.abs() - position.added_margin;
+            position.margin_value = margin_new + position.added_margin;
+            position.leverage = request.leverage;
+            self.account
+                .margin_balance(&contract_type, &request.symbol)
+                .credit(margin_old - margin_new);
+            self.update_liquidation_price(&key);
+        }
+        for order in self.order_list.inner.iter_mut() {
+            if order.symbol == request.symbol
+                && order.position_side == request.position_side
+                && order.exchange == request.exchange
+                && order.contract_type == contract_type
+            {
+                order.leverage = request.leverage;
+                if order.side == OrderSide::Buy && order.stop_price.is_none() {
+                    order.margin = order.margin_needed(order.price, order.amount);
+                }
+            }
+        }
+        self.leverages.insert(key, request.leverage);
+        BacktestResponse::normal_response(format!(
+            "{} {:?} leverage set to {}.",
+            request.symbol, request.position_side, request.leverage
+        ))
+    }
+
+    // contract type of a position request, the one of the open position when the request has none
+    fn request_contract_type(
+        &self,
+        contract_type: &Option<ContractType>,
+        key: &(String, PositionSide, Exchange),
+    ) -> Result<ContractType, String> {
+        if let Some(contract_type) = contract_type {
+            return Ok(contract_type.clone());
+        }
+        self.account
+            .position
+            .get(key)
+            .filter(|x| x.amount_total != dec!(0) && x.contract_type != ContractType::Spot)
+            .map(|x| x.contract_type.clone())
+            .ok_or_else(|| "Missing contract_type, no position to take it from.".to_string())
+    }
+
+    // margin moved between the balance and an isolated position, the liquidation price moves with it
+    pub fn adjust_margin(&mut self, request: AdjustMargin, add: bool) -> BacktestResponse {
+        let key = (request.symbol.clone(), request.position_side.clone(), request.exchange);
+        let contract_type = match self.request_contract_type(&request.contract_type, &key) {
+            Ok(contract_type) => contract_type,
+            Err(e) => return BacktestResponse::bad_request(e),
+        };
+        if contract_type == ContractType::Spot || contract_type == ContractType::Options {
+            return BacktestResponse::bad_request(format!(
+                "Margin is not supported for {:?}.",
+                contract_type
+            ));
+        }
+        let amount = Decimal::from_f64(request.amount).unwrap_or(dec!(0)).round_dp(12);
+        if amount <= dec!(0) {
+            return BacktestResponse::bad_request("Invalid margin amount.".to_string());
+        }
+        match self.account.position.get(&key) {
+            Some(position) if position.amount_total != dec!(0) => {
+                // cross positions share the balance, they have no margin of their own to move
+                if position.margin_mode != MarginMode::Isolated {
+                    return BacktestResponse::bad_request("Margin can only be adjusted on isolated positions.".to_string());
+                }
+                if !add && amount > position.added_margin {
+                    return BacktestResponse::bad_request("Insufficient added margin.".to_string());
+                }
+            }
+            _ => return BacktestResponse::bad_request("Position not found.".to_string()),
+        }
+        let balance = self.account.margin_balance(&contract_type, &request.symbol);
+        if add {
+            if amount > balance.get_available() {
+                return BacktestResponse::bad_request("Insufficient balance.".to_string());
+            }
+            balance.credit(-amount);
+        } else {
+            balance.credit(amount);
+        }
+        let position = self.account.position.get_mut(&key).unwrap();
+        let delta = if add { amount } else { -amount };
+        position.added_margin += delta;
+        position.margin_value += delta;
+        self.update_liquidation_price(&key);
+        let position = &self.account.position[&key];
+        BacktestResponse::normal_response(format!(
+            "{} {:?} margin {}, liquidation price {}.",
+            request.symbol, request.position_side, position.margin_value, position.liquidation_price
+        ))
+    }
+
+    fn update_liquidation_price(&mut self, key: &(String, PositionSide, Exchange)) {
+        let maintenance = self
+            .instruments
+            .get(key.2, &key.0)
+            .and_then(|spec| Decimal::from_f64(spec.maintenance_margin))
+            .unwrap_or(dec!(0));
+        if let Some(position) = self.account.position.get_mut(key) {
+            position.update_liquidation_price(maintenance);
+        }
+    }
+
+    // deposit, or withdrawal when amount is negative
+    fn apply_transfer(&mut self, currency: &str, amount: f64) -> Result<Decimal, String> {
+        let amount = Decimal::from_f64(amount).unwrap_or(dec!(0)).round_dp(12);
+        if amount == dec!(0) {
+            return Err("Invalid transfer amount.".to_string());
+        }
+        let main = currency.is_empty() || currency == self.account.currency;
+        let balance = self.account.currency_balance(currency);
+        if -amount > balance.get_available() {
+            return Err("Insufficient balance.".to_string());
+        }
+        balance.credit(amount);
+        if main {
+            self.account.transfers += amount;
+        }
+        Ok(amount)
+    }
+
+    pub fn deposit(&mut self, transfer: Transfer) -> BacktestResponse {
+        if transfer.amount <= 0.0 {
+            return BacktestResponse::bad_request("Invalid transfer amount.".to_string());
+        }
+        match self.apply_transfer(&transfer.currency, transfer.amount) {
+            Ok(amount) => BacktestResponse::normal_response(format!("deposited {}.", amount)),
+            Err(e) => BacktestResponse::bad_request(e),
+        }
+    }
+
+    pub fn withdraw(&mut self, transfer: Transfer) -> BacktestResponse {
+        if transfer.amount <= 0.0 {
+            return BacktestResponse::bad_request("Invalid transfer amount.".to_string());
+        }
+        match self.apply_transfer(&transfer.currency, -transfer.amount) {
+            Ok(amount) => BacktestResponse::normal_response(format!("withdrew {}.", -amount)),
+            Err(e) => BacktestResponse::bad_request(e),
+        }
+    }
+
+    // transfers of the config due at timestamp, withdrawals over the available balance are skipped
+    fn process_transfers(&mut self, timestamp: i64) {
+        while self.transfers.front().is_some_and(|x| x.timestamp <= timestamp) {
+            let transfer = self.transfers.pop_front().unwrap();
+            if let Err(e) = self.apply_transfer(&transfer.currency, transfer.amount) {
+                log::warn!("transfer {:?} skipped: {}", transfer, e);
+            }
+        }
+    }
+
+    // cancel the orders of a position and flatten it at once against the book the client sees
+    // a deliberate zero latency close: the cancels and the close order skip the order latency
+    // and fill against the book of the last delivered tick, so the fills can be replied at once
+    pub fn close_position(&mut self, request: ClosePosition) -> BacktestResponse {
+        let key = (request.symbol.clone(), request.position_side.clone(), request.exchange);
+        // spot holdings are only sold when asked for, never as a fallback of a missing position
+        let position_type = self
+            .account
+            .position
+            .get(&key)
+            .filter(|x| x.amount_total != dec!(0) && x.contract_type != ContractType::Spot)
+            .map(|x| x.contract_type.clone());
+        let Some(contract_type) = request.contract_type.clone().or(position_type) else {
+            return BacktestResponse::bad_request("No position to close.".to_string());
+        };
+        let spot = contract_type == ContractType::Spot;
+        // the tick computed ahead is hidden from the client
+        let depth = self.view.depth.clone();
+        let cids: Vec<String> = self
+            .order_list
+            .inner
+            .iter()
+            .filter(|x| {
+                x.exchange == request.exchange
+                    && x.symbol == request.symbol
+                    && x.contract_type == contract_type
+                    && (spot || x.position_side == request.position_side)
+                    && (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+            })
+            .map(|x| x.cid.clone())
+            .collect();
+        for cid in cids {
+            self.cancel_order_now(cid);
+        }
+        let (amount, leverage) = if spot {
+            (self.account.holding(&request.symbol).get_available(), 1)
+        } else {
+            self.account
+                .position
+                .get(&key)
+                .map_or((dec!(0), 1), |x| (x.amount_available, x.leverage.max(1)))
+        };
+        let amount = self.floor_step(request.exchange, &request.symbol, amount.to_f64().unwrap_or(0.0));
+        if amount <= 0.0 {
+            return BacktestResponse::bad_request("No position to close.".to_string());
+        }
+        let mut order = Order {
+            cid: request
+                .cid
+                .clone()
+                .unwrap_or_else(|| format!("close-{}-{:?}-{}", request.symbol, request.position_side, depth.local_timestamp)),
+            exchange: request.exchange,
+            symbol: request.symbol.clone(),
+            contract_type,
+            position_side: request.position_side.clone(),
+            side: OrderSide::Sell,
+            amount,
+            leverage,
+            timestamp: depth.local_timestamp,
+            front_amount: -1.0,
+            ..Default::default()
+        };
+        // market closes take the price through the whole book
+        match request.price {
+            Some(price) => order.price = price,
+            None => {
+                let levels = if order.is_buy_direction() { &depth.asks } else { &depth.bids };
+                let Some(worst) = levels.last() else {
+                    return BacktestResponse::bad_request("No liquidity to close.".to_string());
+                };
+                order.price = worst.0;
+                order.order_type = OrderType::Market;
+            }
+        }
+        let cid = order.cid.clone();
+        let response = self.post_order(order);
+        if response.status != BacktestStatus::Ok {
+            return response;
+        }
+        let Some(mut order) = self.order_list.remove_order(cid.clone()) else {
+            return response;
+        };
+        order.timestamp = depth.local_timestamp;
+        let mut closing = OrderList::default();
+        closing.insert_order(order);
+        let filled = closing.execute_orders(&depth, &[], self.queue_model.as_ref(), &mut self.liquidity, &mut self.rng);
+        for order in closing.inner {
+            self.order_list.insert_order(order);
+        }
+        let fills = self.settle_fills(filled, depth.local_timestamp);
+        BacktestResponse::normal_response(
+            sonic_rs::to_string(&CloseResponse { cid, fills }).unwrap_or_else(|_| "{}".to_string()),
+        )
+    }
+
+    fn match_orders(&mut self) {
+        self.trigger_stop_orders();
+        let depth = &mut self.depth.clone();
+        // fills on a trade tick happen at the time of the print
+        if let Some(trade) = self.recent_trades.last() {
+            depth.local_timestamp = depth.local_timestamp.max(trade.local_timestamp);
+        }
+        // when tick update, try to match orders, the queue model gets the trades since the last match
+        let filled_stack = self.order_list.execute_orders(
+            depth,
+            &self.recent_trades,
+            self.queue_model.as_ref(),
+            &mut self.liquidity,
+            &mut self.rng,
+        );
+        self.recent_trades.clear();
+        self.settle_fills(filled_stack, depth.local_timestamp);
+    }
+
+    fn settle_fills(&mut self, filled_stack: Vec<FilledStack>, timestamp: i64) -> Vec<FilledStack> {
+        if !filled_stack.is_empty() {
+            debug!("{:?}", filled_stack);
+        }
+        let mut settled = Vec::with_capacity(filled_stack.len());
+        for mut filled in filled_stack {
+            let rate = Decimal::from_f64(self.config.fee_rate.rate(filled.maker)).unwrap_or(dec!(0));
+            filled.fee = (filled.notional() * rate).round_dp(12);
+            filled.timestamp = timestamp;
+            self.settle_position(&filled);
+            // info!("current first orders: {:?}", self.order_list.inner);
+            // if order too much, warning!
+            if self.order_list.inner.len() > 1000 {
+                log::warn!(
+                    "Order list too long: {}, consider to cancel some orders.",
+                    self.order_list.inner.len()
+                );
+            }
+            // parent progress of algo children
+            if let Some(algo) = self.algos.iter_mut().find(|x| x.is_child(&filled.cid)) {
+                // filled amount is positive when taking the asks
+                algo.on_fill(
+                    filled.filled_price.to_f64().unwrap_or(0.0),
+                    filled.filled_amount.to_f64().unwrap_or(0.0),
+                    filled.filled_amount > dec!(0),
+                );
+            }
+            // one cancels other
+            self.reduce_oco_siblings(&filled);
+            // take profit and stop loss legs of a bracket order
+            if (filled.take_profit.is_some() || filled.stop_loss.is_some())
+                && ((filled.filled_amount > dec!(0) && filled.side == PositionSide::Long)
+                    || (filled.filled_amount < dec!(0) && filled.side == PositionSide::Short))
+            {
+                self.post_bracket_legs(&filled, timestamp);
+            }
+            self.log_fill(&filled);
+            settled.push(filled);
+        }
+        // self.account.judege_close((depth.bids[0].0 + depth.asks[0].0) / 2.0, depth.symbol.clone());
+        settled
+    }
+
+    // balances and position of a fill
+    fn settle_position(&mut self, filled: &FilledStack) {
+        self.account.fill_freezed(filled);
+        let key = (filled.symbol.clone(), filled.side.clone(), filled.exchange);
+        let account = self.account.position.entry(key.clone()).or_default();
+        // spot holdings are in the wallet, the position keeps their entry price and pnl
+        if filled.contract_type == ContractType::Spot {
+            account.update_pos(filled);
+            return;
+        }
+        let mut released = account.release_added_margin(filled);
+        let margin = account.margin_value;
+        account.update_pos(filled);
+        // closes of short options give back their share of the margin
+        if filled.contract_type == ContractType::Options
+            && filled.side == PositionSide::Short
+            && filled.filled_amount > dec!(0)
+        {
+            released += margin - account.margin_value;
+        }
+        self.account
+            .margin_balance(&filled.contract_type, &filled.symbol)
+            .credit(released);
+        self.update_liquidation_price(&key);
+    }
+
+    fn cancel_instrument_orders(&mut self, exchange: Exchange, symbol: &str) {
+        let cids: Vec<String> = self
+            .order_list
+            .inner
+            .iter()
+            .filter(|x| {
+                x.exchange == exchange
+                    && x.symbol == symbol
+                    && (x.state == OrderState::Open || x.state == OrderState::PartiallyFilled)
+            })
+            .map(|x| x.cid.clone())
+            .collect();
+        for cid in cids {
+            self.cancel_order_now(cid);
+        }
+    }
+
+    // dated futures past expiry: open orders are canceled, positions closed at the delivery price and removed
+    fn deliver_expired_futures(&mut self, timestamp: i64) {
+        let expiring: Vec<InstrumentSpec> = self
+            .instruments
+            .dated()
+            .filter(|spec| {
+                spec.expiry.is_some_and(|x| x <= timestamp)
+                    && !self.expired.contains(&(spec.exchange, spec.symbol.clone()))
+            })
+            .cloned()
+            .collect();
+        for spec in expiring {
+            // configured, index, last trade, then the last mid of the contract
+            let Some(price) = spec
+                .delivery_price
+                .or_else(|| self.indexes.get(&index_key(&spec.underlying)).copied())
+                .or_else(|| self.last_prices.get(&index_key(&spec.symbol)).copied())
+                .or_else(|| self.indexes.get(&index_key(&spec.symbol)).copied())
+            else {
+                log::warn!("no price to deliver {} at expiry", spec.symbol);
+                continue;
+            };
+            self.expired.insert((spec.exchange, spec.symbol.clone()));
+            self.cancel_instrument_orders(spec.exchange, &spec.symbol);
+            let price = Decimal::from_f64(price).unwrap_or(dec!(0));
+            for side in [PositionSide::Long, PositionSide::Short] {
+                let key = (spec.symbol.clone(), side.clone(), spec.exchange);
+                let Some(position) = self.account.position.get_mut(&key) else {
+                    continue;
+                };
+                let amount = position.amount_total;
+                if amount != dec!(0) {
+                    let available = position.amount_available;
+                    position.add_freezed(available);
+                    let contract_type = if position.contract_type == ContractType::Inverse {
+                        ContractType::Inverse
+                    } else {
+                        ContractType::Futures
+                    };
+                    let filled = FilledStack {
+                        cid: format!("{}-delivery", spec.symbol),
+                        exchange: spec.exchange,
+                        symbol: spec.symbol.clone(),
+                        contract_type,
+                        leverage: position.leverage.max(1),
+                        open_price: Some(position.entry_price),
+                        filled_price: price,
+                        filled_amount: if side == PositionSide::Long { -amount } else { amount },
+                        post_price: price,
+                        amount_total: amount,
+                        multiplier: Decimal::from_f64(spec.multiplier).unwrap_or(dec!(1)),
+                        maker: true,
+                        side,
+                        timestamp,
+                        ..Default::default()
+                    };
+                    self.settle_position(&filled);
+                    self.log_fill(&filled);
+                }
+                if let Some(position) = self.account.position.remove(&key) {
+                    if position.contract_type != ContractType::Inverse {
+                        self.account.delivered_pnl += position.realized_pnl;
+                        self.account.delivered_funding += position.funding;
+                    }
+                }
+            }
+        }
+    }
+
+    // options of the instruments past expiry: open orders are canceled, positions closed at the intrinsic value
+    fn settle_expired_options(&mut self, timestamp: i64) {
+        let expiring: Vec<InstrumentSpec> = self
+            .instruments
+            .options()
+            .filter(|spec| {
+                spec.option.as_ref().is_some_and(|x| x.expiry <= timestamp)
+                    && !self.expired.contains(&(spec.exchange, spec.symbol.clone()))
+            })
+            .cloned()
+            .collect();
+        for spec in expiring {
+            let Some(option) = spec.option.clone() else {
+                continue;
+            };
+            let Some(index) = option
+                .settlement_price
+                .or_else(|| self.indexes.get(&index_key(&option.underlying)).copied())
+            else {
+                log::warn!("no index to settle {} at expiry", spec.symbol);
+                continue;
+            };
+            self.expired.insert((spec.exchange, spec.symbol.clone()));
+            self.cancel_instrument_orders(spec.exchange, &spec.symbol);
+            let intrinsic = Decimal::from_f64(option.intrinsic(index)).unwrap_or(dec!(0));
+            for side in [PositionSide::Long, PositionSide::Short] {
+                let key = (spec.symbol.clone(), side.clone(), spec.exchange);
+                let Some(position) = self.account.position.get_mut(&key) else {
+                    continue;
+                };
+                let amount = position.amount_total;
+                if amount == dec!(0) {
+                    continue;
+                }
+                let available = position.amount_available;
+                position.add_freezed(available);
+                let filled = FilledStack {
+                    cid: format!("{}-expiry", spec.symbol),
+                    exchange: spec.exchange,
+                    symbol: spec.symbol.clone(),
+                    contract_type: ContractType::Options,
+                    leverage: 1,
+                    open_price: Some(position.entry_price),
+                    filled_price: intrinsic,
+                    filled_amount: if side == PositionSide::Long { -amount } else { amount },
+                    post_price: intrinsic,
+                    amount_total: amount,
+                    multiplier: Decimal::from_f64(spec.multiplier).unwrap_or(dec!(1)),
+                    maker: true,
+                    side,
+                    timestamp,
+                    ..Default::default()
+                };
+                self.settle_position(&filled);
+                self.log_fill(&filled);
+            }
+        }
+    }
+
+    pub fn post_algo(&mut self, mut algo: AlgoOrder) -> BacktestResponse {
+        if let Err(e) = algo.validate() {
+            return BacktestResponse::bad_request(e);
+        }
+        if self.algos.iter().any(|x| x.algo_id == algo.algo_id) {
+            return BacktestResponse::bad_request("Algo id already exists.".to_string());
+        }
+        if self.floor_step(algo.exchange, &algo.symbol, algo.amount) != algo.amount {
+            return BacktestResponse::bad_request("Invalid algo amount, not a whole number of steps.".to_string());
+        }
+        // the traded volume comes from the trade data
+        if algo.algo_type == AlgoType::Vwap && !self.zconfig.use_trade {
+            return BacktestResponse::bad_request("VWAP needs use_trade.".to_string());
+        }
+        let arrival_price = if self.depth.asks.is_empty() || self.depth.bids.is_empty() {
+            0.0
+        } else {
+            (self.depth.asks[0].0 + self.depth.bids[0].0) / 2.0
+        };
+        algo.start(arrival_price);
+        let algo_id = algo.algo_id.clone();
+        self.algos.push(algo);
+        BacktestResponse::normal_response(format!("algo: {} posted.", algo_id))
+    }
+
+    pub fn cancel_algo(&mut self, algo_id: String) -> BacktestResponse {
+        let Some(index) = self.algos.iter().position(|x| x.algo_id == algo_id) else {
+            return BacktestResponse::bad_request("Algo not found".to_string());
+        };
+        if self.algos[index].state != AlgoState::Running {
+            return BacktestResponse::normal_response("Algo already finished or Canceled.".to_string());
+        }
+        for cid in self.algos[index].children().to_vec() {
+            if self.order_list.get_open_mut(&cid).is_some() {
+                self.cancel_order_now(cid);
+            }
+        }
+        self.algos[index].state = AlgoState::Canceled;
+        BacktestResponse::normal_response(format!("algo: {} canceled.", algo_id))
+    }
+
+    // post the next child of the algos whose slice is due, the rest of the last child is canceled first
+    fn run_algos(&mut self) {
+        let depth = self.depth.clone();
+        for index in 0..self.algos.len() {
+            if self.algos[index].exchange != depth.exchange || !self.algos[index].is_due(depth.local_timestamp) {
+                continue;
+            }
+            for cid in self.algos[index].children().to_vec() {
+                if self.order_list.get_open_mut(&cid).is_some() {
+                    self.cancel_order_now(cid);
+                }
+            }
+            if self.algos[index].expire() {
+                continue;
+            }
+            if let Some(mut child) = self.algos[index].slice(&depth) {
+                child.amount = self.floor_step(child.exchange, &child.symbol, child.amount);
+                if child.amount <= 0.0 {
+                    continue;
+                }
+                let cid = child.cid.clone();
+                let response = self.submit_order(child);
+                if response.status != BacktestStatus::Ok {
+                    log::warn!("algo child {} post failed: {}", cid, response.message);
+                    continue;
+                }
+                self.algos[index].adopt(cid.clone());
+                let open_cid = format!("{}-open", cid);
+                if self.order_list.get_open_mut(&open_cid).is_some() {
+                    self.algos[index].adopt(open_cid);
+                }
+            }
+        }
+    }
+
+    // post the stop orders crossed by current depth as normal orders, cancel their oco siblings first
+    fn trigger_stop_orders(&mut self) {
+        let depth = self.depth.clone();
+        for mut order in self.order_list.take_triggered(&depth) {
+            if let Some(group) = order.oco_group.as_ref() {
+                for cid in self.order_list.oco_siblings(group, &order.cid) {
+                    self.cancel_order_now(cid);
+                }
+            }
+            order.stop_price = None;
+            order.timestamp = depth.local_timestamp;
+            if let OrderType::Market = order.order_type {
+                // market stop, price through the whole book
+                let worst = if order.is_buy_direction() {
+                    depth.asks.last()
+                } else {
+                    depth.bids.last()
+                };
+                if let Some(level) = worst {
+                    order.price = level.0;
+                }
+            }
+            let cid = order.cid.clone();
+            let response = if order.one_way {
+                order.one_way = false;
+                self.post_one_way(order)
+            } else {
+                self.post_order(order)
+            };
+            if response.status != BacktestStatus::Ok {
+                log::warn!("stop order {} trigger failed: {}", cid, response.message);
+            }
+        }
+    }
+
+    // a fill of an oco leg takes the same amount off the other legs, they are canceled once the leg is filled
+    fn reduce_oco_siblings(&mut self, filled: &FilledStack) {
+        let Some(group) = filled.oco_group.as_ref() else {
+            return;
+        };
+        let done = self
+            .order_list
+            .inner
+            .iter()
+            .find(|x| x.cid == filled.cid)
+            .is_none_or(|x| x.state == OrderState::Filled);
+        let amount = filled.filled_amount.abs().to_f64().unwrap_or(0.0);
+        for cid in self.order_list.oco_siblings(group, &filled.cid) {
+            let Some((total, traded)) = self.order_list.get_open_mut(&cid).map(|x| (x.amount, x.filled_amount)) else {
+                continue;
+            };
+            let rest = round6(total - amount);
+            if done || rest <= traded {
+                self.cancel_order_now(cid);
+                continue;
+            }
+            // the exchange reduces the other legs at once
+            let response = self.amend_now(AmendOrder { cid: cid.clone(), price: None, amount: Some(rest) }, self.depth.local_timestamp);
+            if response.status != BacktestStatus::Ok {
+                log::warn!("oco leg {} not reduced: {}", cid, response.message);
+            }
+        }
+    }
+
+    // the legs share an oco group, the legs of later partial fills are merged into the open ones
+    fn post_bracket_legs(&mut self, filled: &FilledStack, timestamp: i64) {
+        let group = format!("bk-{}", filled.cid);
+        let amount = filled.filled_amount.abs();
+        if let Some(profit) = filled.take_profit {
+            let cid = format!("tp-{}", filled.cid);
+            if let Some(leg) = self.order_list.get_open_mut(&cid) {
+                leg.amount += amount.to_f64().unwrap_or(0.0);
+                if filled.contract_type == ContractType::Spot {
+                    self.account.holding(&filled.symbol).add_freezed(amount);
+                } else {
+                    self.account
+                        .position
+                        .entry((filled.symbol.clone(), filled.side.clone(), filled.exchange))
+                        .or_default()
+                        .add_freezed(amount);
+                }
+            } else {
+                let take_profit_order = Order {
+                    cid,
+                    exchange: filled.exchange,
+                    symbol: filled.symbol.clone(),
+                    position_side: filled.side.clone(),
+                    contract_type: filled.contract_type.clone(),
                     side: OrderSide::Sell,
-                    price: filled.take_profit.unwrap().to_f64().unwrap_or(0.0),
-                    amount: filled.filled_amount.abs().to_f64().unwrap_or(0.0),
+                    price: profit.to_f64().unwrap_or(0.0),
+                    amount: amount.to_f64().unwrap_or(0.0),
                     leverage: filled.leverage,
-                    timestamp: depth.local_timestamp,
+                    timestamp,
                     margin: filled.freeze_margin.to_f64().unwrap_or(0.0),
                     state: OrderState::Open,
+                    oco_group: Some(group.clone()),
                     ..Default::default()
                 };
                 self.post_order(take_profit_order);
             }
         }
-        // self.account.judege_close((depth.bids[0].0 + depth.asks[0].0) / 2.0, depth.symbol.clone());
+        if let Some(loss) = filled.stop_loss {
+            let cid = format!("sl-{}", filled.cid);
+            if let Some(leg) = self.order_list.get_open_mut(&cid) {
+                leg.amount += amount.to_f64().unwrap_or(0.0);
+            } else {
+                let stop_loss_order = Order {
+                    cid,
+                    exchange: filled.exchange,
+                    symbol: filled.symbol.clone(),
+                    position_side: filled.side.clone(),
+                    contract_type: filled.contract_type.clone(),
+                    side: OrderSide::Sell,
+                    order_type: OrderType::Market,
+                    price: loss.to_f64().unwrap_or(0.0),
+                    stop_price: loss.to_f64(),
+                    amount: amount.to_f64().unwrap_or(0.0),
+                    leverage: filled.leverage,
+                    timestamp,
+                    state: OrderState::Open,
+                    oco_group: Some(group),
+                    ..Default::default()
+                };
+                self.post_order(stop_loss_order);
+            }
+        }
     }
 
     
@@ -812,11 +2500,890 @@ mod tests {
                 "taker_fee": 0
             }
         }"#;
-        let config = BtConfig::parse(config_str);
+        let mut config = BtConfig::parse(config_str);
         info!("{:?}", config);
+        assert_eq!(config.latency, super::LatencyModel::Fixed(20));
+        assert_eq!(config.fill_model, super::FillModel::PowerProbQueueFunc3(3.0));
+        assert_eq!(config.feed_latency, super::LatencyModel::None);
+        assert_eq!(config.cancel_latency, super::LatencyModel::None);
+        assert_eq!(config.ack_latency, super::LatencyModel::None);
+        assert_eq!(config.balance_currency(), "USDT");
+        config.symbol = "ETH_FDUSD".to_string();
+        assert_eq!(config.balance_currency(), "FDUSD");
+    }
+
+    #[test]
+    fn test_validate_btconfig_models() {
+        let config_str = r#"{
+            "exchanges": ["BinanceSpot"],
+            "symbol": "BTC_USDT",
+            "start_time": 0,
+            "end_time": 0,
+            "balance": {"total": 0, "available": 0, "freezed": 0},
+            "fee_rate": {"maker_fee": 0, "taker_fee": 0},
+            "latency": {"Random": [10, 50]},
+            "fill_model": {"PowerProbQueueFunc": 2.0}
+        }"#;
+        let mut config: BtConfig = sonic_rs::from_str(config_str).unwrap();
+        assert_eq!(config.latency, super::LatencyModel::Random(10, 50));
+        assert!(config.validate().is_ok());
+        config.latency = super::LatencyModel::Random(50, 10);
+        assert!(config.validate().is_err());
+        config.latency = super::LatencyModel::None;
+        config.cancel_latency = super::LatencyModel::Random(50, 10);
+        assert!(config.validate().is_err());
+        config.cancel_latency = super::LatencyModel::None;
+        config.fill_model = super::FillModel::Random(0.5, 0.2);
+        assert!(config.validate().is_err());
+        config.fill_model = super::FillModel::default();
+        config.queue_model = crate::queue::QueueModelType::Trade;
+        assert!(config.validate().is_ok());
+        assert!(config.prepare(&crate::ZConfig::default()).is_err());
+        config.fill_model = super::FillModel::None;
+        assert!(config.validate().is_err());
+        config.fill_model = super::FillModel::default();
+        config.queue_model = crate::queue::QueueModelType::Depth;
+        config.impact = Some(super::ImpactModel { temporary: 0.5, ..Default::default() });
+        assert!(config.validate().is_err());
+        config.impact = Some(super::ImpactModel { temporary: 0.5, half_life: 1_000_000, ..Default::default() });
+        assert!(config.validate().is_ok());
+        // the instruments file of the server is read at launch
+        let zconfig = crate::ZConfig { instruments: Some("missing.json".to_string()), ..Default::default() };
+        assert!(config.prepare(&zconfig).is_err());
+    }
+
+    #[test]
+    fn test_empirical_latency_models() {
+        use super::LatencyModel;
+        let histogram = LatencyModel::Histogram(vec![(5, 0.0), (30, 1.0)]);
+        assert!(histogram.validate().is_ok());
+        assert_eq!(histogram.latency(0, 0.0, &mut rand::thread_rng()), 30);
+        assert!(LatencyModel::Empirical(vec![]).validate().is_err());
+
+        // 8:00 to 20:00 utc is slow, the rest wraps to the 20:00 bucket
+        let by_hour = LatencyModel::TimeOfDay(vec![(8, LatencyModel::Fixed(50)), (20, LatencyModel::Fixed(10))]);
+        assert_eq!(by_hour.latency(9 * 3_600_000_000, 0.0, &mut rand::thread_rng()), 50);
+        assert_eq!(by_hour.latency(3 * 3_600_000_000, 0.0, &mut rand::thread_rng()), 10);
+        let by_rate = LatencyModel::MessageRate(vec![(0.0, LatencyModel::Fixed(5)), (100.0, by_hour)]);
+        assert!(by_rate.validate().is_ok());
+        assert_eq!(by_rate.latency(9 * 3_600_000_000, 10.0, &mut rand::thread_rng()), 5);
+        assert_eq!(by_rate.latency(9 * 3_600_000_000, 150.0, &mut rand::thread_rng()), 50);
+
+        let data_dir = std::env::temp_dir().join("zilean_test_data");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::write(data_dir.join("latency.txt"), "# recorded\n12\n12.2\n").unwrap();
+        std::fs::write(data_dir.join("bad.txt"), "12\nsecret\n").unwrap();
+        std::fs::write(std::env::temp_dir().join("zilean_test_outside.txt"), "12\n").unwrap();
+        let data_dir = data_dir.to_str();
+        let file = |path: &str| LatencyModel::File {
+            path: path.to_string(),
+            samples: vec![],
+        };
+        assert!(file("latency.txt").load(None).is_err());
+        assert!(file("../zilean_test_outside.txt").load(data_dir).is_err());
+        // the line is reported, not its content
+        assert_eq!(file("bad.txt").load(data_dir), Err("Invalid latency on line 2 of bad.txt.".to_string()));
+        let mut file = file("latency.txt");
+        file.load(data_dir).unwrap();
+        assert!(file.validate().is_ok());
+        assert_eq!(file.latency(0, 0.0, &mut rand::thread_rng()), 12);
+        // only the path is echoed back
+        assert!(!sonic_rs::to_string(&file).unwrap().contains("samples"));
+    }
+
+    #[test]
+    fn test_seeded_models_reproducible() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let latency = super::LatencyModel::Random(10, 1000);
+        let fill_model = super::FillModel::Random(0.0, 1.0);
+        let draws = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .map(|_| (latency.latency(0, 0.0, &mut rng), fill_model.prob(1.0, 1.0, &mut rng)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
     }
     use super::ZileanV1;
 
+    // futures backtest of BTC_USDT on BinanceSwap without fees nor latency, the fields of `overrides` on top
+    fn test_config(overrides: &str) -> BtConfig {
+        use sonic_rs::{JsonContainerTrait, JsonValueMutTrait};
+        let mut config: sonic_rs::Value = sonic_rs::from_str(
+            r#"{
+            "exchanges": ["BinanceSwap"],
+            "symbol": "BTC_USDT",
+            "start_time": 0,
+            "end_time": 0,
+            "balance": {"total": 1000, "available": 1000, "freezed": 0},
+            "source": {"FilePath": "none"},
+            "fee_rate": {"maker_fee": 0, "taker_fee": 0},
+            "latency": "None"
+        }"#,
+        )
+        .unwrap();
+        let overrides: sonic_rs::Value = sonic_rs::from_str(overrides).unwrap();
+        let fields = config.as_object_mut().unwrap();
+        for (key, value) in overrides.as_object().unwrap().iter() {
+            fields.insert(key, value.clone());
+        }
+        sonic_rs::from_value(&config).unwrap()
+    }
+
+    // engine on a file source, nothing is loaded until launch
+    async fn test_engine(overrides: &str) -> ZileanV1 {
+        let config = test_config(overrides);
+        let mut zilean = ZileanV1::new(config.clone(), crate::ZConfig::default()).await;
+        zilean.account.balance = config.balance.clone();
+        zilean.account.currency = config.balance_currency();
+        zilean.depth = crate::market::Depth {
+            exchange: crate::market::Exchange::BinanceSwap,
+            bids: vec![(99.0, 10.0)],
+            asks: vec![(101.0, 10.0)],
+            local_timestamp: 1,
+            ..Default::default()
+        };
+        zilean
+    }
+
+    // futures order of the test config at 1x, posted at 1
+    fn test_order(cid: &str, side: crate::market::OrderSide, price: f64, amount: f64) -> crate::market::Order {
+        use crate::market::*;
+        Order {
+            cid: cid.to_string(),
+            contract_type: ContractType::Futures,
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            side,
+            price,
+            amount,
+            leverage: 1,
+            timestamp: 1,
+            front_amount: -1.0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bracket_legs() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine("{}").await;
+        let entry = Order {
+            take_profit: Some(105.0),
+            stop_loss: Some(95.0),
+            ..test_order("1", OrderSide::Buy, 101.0, 2.0)
+        };
+        zilean.post_order(entry);
+        zilean.match_orders();
+        let key = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        assert_eq!(zilean.account.position[&key].amount_freezed, dec!(2));
+        assert_eq!(zilean.order_list.get_open_mut("sl-1").map(|x| x.amount), Some(2.0));
+
+        // half of the take profit fills, the stop loss keeps covering the rest
+        zilean.depth.bids = vec![(105.0, 1.0)];
+        zilean.depth.local_timestamp = 2;
+        zilean.match_orders();
+        assert_eq!(zilean.order_list.get_open_mut("tp-1").map(|x| x.filled_amount), Some(1.0));
+        assert_eq!(zilean.order_list.get_open_mut("sl-1").map(|x| x.amount), Some(1.0));
+
+        // the take profit is filled, the stop loss is canceled
+        zilean.depth.bids = vec![(106.0, 5.0)];
+        zilean.depth.local_timestamp = 3;
+        zilean.match_orders();
+        assert!(zilean.order_list.get_open_mut("tp-1").is_none());
+        assert!(zilean.order_list.get_open_mut("sl-1").is_none());
+        assert_eq!(zilean.account.position[&key].amount_total, dec!(0));
+        assert_eq!(zilean.account.position[&key].amount_freezed, dec!(0));
+        // in at 101, out at the bids of 105 and 106
+        assert_eq!(zilean.account.balance.get_available(), dec!(1009));
+    }
+
+    #[tokio::test]
+    async fn test_trade_queue_matching() {
+        use crate::market::*;
+        let mut zilean = test_engine(r#"{"queue_model": "Trade"}"#).await;
+        zilean.post_order(test_order("1", OrderSide::Buy, 99.0, 2.0));
+        // joins the queue behind the 10 on the bids
+        zilean.match_orders();
+        let print = |side: &str, amount: f64, local_timestamp: i64| Trade {
+            exchange: "binance-futures".to_string(),
+            symbol: "BTC_USDT".to_string(),
+            local_timestamp,
+            price: 99.0,
+            amount,
+            side: side.to_string(),
+            ..Default::default()
+        };
+        // a taker buy does not touch the bids
+        zilean.recent_trades.push(print("buy", 20.0, 2));
+        zilean.match_orders();
+        assert_eq!(zilean.order_list.get_open_mut("1").map(|x| x.front_amount), Some(10.0));
+        // the print uses up the queue ahead, then fills us at the time of the print
+        zilean.recent_trades.push(print("sell", 11.0, 3));
+        zilean.match_orders();
+        assert_eq!(zilean.order_list.get_open_mut("1").map(|x| x.filled_amount), Some(1.0));
+        assert_eq!(zilean.fills.back().map(|x| (x.timestamp, x.maker)), Some((3, true)));
+        zilean.recent_trades.push(print("sell", 5.0, 4));
+        zilean.match_orders();
+        assert!(zilean.order_list.get_open_mut("1").is_none());
+        assert!(zilean.recent_trades.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_post_algo() {
+        use crate::algo::*;
+        let mut zilean = test_engine("{}").await;
+        let algo = |algo_type: &str| -> AlgoOrder {
+            sonic_rs::from_str(&format!(
+                r#"{{"algo_id": "{0}", "algo_type": "{0}", "contract_type": "Futures", "exchange": "BinanceSwap",
+                "symbol": "BTC_USDT", "side": "Buy", "amount": 100, "start_time": 0, "end_time": 100, "interval": 25,
+                "participation": 0.1}}"#,
+                algo_type
+            ))
+            .unwrap()
+        };
+        assert_eq!(zilean.post_algo(algo("Vwap")).message, "VWAP needs use_trade.");
+
+        // the child is over the balance, it is not counted as posted
+        zilean.post_algo(algo("Twap"));
+        zilean.depth.local_timestamp = 50;
+        zilean.run_algos();
+        assert!(zilean.algos[0].children().is_empty());
+        assert!(zilean.order_list.get_open_mut("Twap-0").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_contract_multiplier() {
+        use crate::market::*;
+        use crate::server::BacktestStatus;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{"instruments": [{"exchange": "BinanceSwap", "symbol": "BTC_USDT", "tick_size": 0.1, "step_size": 0.001, "multiplier": 0.01},
+            {"exchange": "BinanceSwap", "symbol": "BTC_USD", "tick_size": 0.1, "step_size": 1, "multiplier": 100}]}"#,
+        )
+        .await;
+        // linear contracts would be margined at the whole amount but checked at 1% of it
+        let response = zilean.post_order(test_order("1", OrderSide::Buy, 100.0, 1.0));
+        assert_eq!(response.status, BacktestStatus::Error);
+        assert_eq!(zilean.account.balance.get_available(), dec!(1000));
+
+        // 10 contracts of 100 usd at 2x freeze 10 * 100 / 100 / 2 btc
+        zilean.account.holding("BTC_USD").credit(dec!(10));
+        let order = Order {
+            contract_type: ContractType::Inverse,
+            symbol: "BTC_USD".to_string(),
+            leverage: 2,
+            ..test_order("2", OrderSide::Buy, 100.0, 10.0)
+        };
+        assert_eq!(zilean.post_order(order).status, BacktestStatus::Ok);
+        assert_eq!(zilean.account.wallet["BTC"].get_available(), dec!(5));
+    }
+
+    #[tokio::test]
+    async fn test_algo_slices_on_step() {
+        use crate::algo::*;
+        use crate::server::BacktestStatus;
+        let mut zilean = test_engine(
+            r#"{"instruments": [{"exchange": "BinanceSwap", "symbol": "BTC_USDT", "tick_size": 1, "step_size": 0.1}]}"#,
+        )
+        .await;
+        let algo = |amount: f64| -> AlgoOrder {
+            sonic_rs::from_str(&format!(
+                r#"{{"algo_id": "1", "algo_type": "Twap", "contract_type": "Futures", "exchange": "BinanceSwap",
+                "symbol": "BTC_USDT", "side": "Buy", "amount": {}, "start_time": 0, "end_time": 300, "interval": 100}}"#,
+                amount
+            ))
+            .unwrap()
+        };
+        assert_eq!(zilean.post_algo(algo(1.05)).status, BacktestStatus::Error);
+        zilean.post_algo(algo(1.0));
+        zilean.depth.local_timestamp = 100;
+        zilean.run_algos();
+        // a third of the amount, down to the step
+        assert_eq!(zilean.order_list.get_open_mut("1-0").map(|x| x.amount), Some(0.3));
+    }
+
+    #[tokio::test]
+    async fn test_spot_quote_currency() {
+        use crate::market::*;
+        use crate::server::BacktestStatus;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(r#"{"symbol": "BTC_USDC"}"#).await;
+        let spot = |cid: &str, side: OrderSide, price: f64| Order {
+            contract_type: ContractType::Spot,
+            symbol: "BTC_USDC".to_string(),
+            ..test_order(cid, side, price, 2.0)
+        };
+        // the balance is in the quote of the symbol
+        assert_eq!(zilean.post_order(spot("1", OrderSide::Buy, 101.0)).status, BacktestStatus::Ok);
+        zilean.match_orders();
+        assert_eq!(zilean.account.wallet["BTC"].get_available(), dec!(2));
+        assert_eq!(zilean.account.balance.get_available(), dec!(798));
+
+        // the position keeps the entry price and pnl of the holding
+        zilean.depth.bids = vec![(106.0, 5.0)];
+        zilean.post_order(spot("2", OrderSide::Sell, 105.0));
+        zilean.match_orders();
+        let position = &zilean.account.position[&("BTC_USDC".to_string(), PositionSide::Long, Exchange::BinanceSwap)];
+        assert_eq!(position.amount_total, dec!(0));
+        assert_eq!(position.realized_pnl, dec!(10));
+        assert_eq!(zilean.account.balance.get_available(), dec!(1010));
+    }
+
+    #[tokio::test]
+    async fn test_pnl_fees_funding() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{"fee_rate": {"maker_fee": 0.0002, "taker_fee": 0.0005}, "funding": {"rate": 0.001, "interval": 10}}"#,
+        )
+        .await;
+        // crossing the spread, taker fee on 101
+        zilean.post_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        zilean.marks.insert((Exchange::BinanceSwap, "BTCUSDT".to_string()), 100.0);
+        zilean.settle_funding(5);
+        zilean.settle_funding(10);
+        zilean.account.mark_to_market(&zilean.marks);
+        let key = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        let position = &zilean.account.position[&key];
+        assert_eq!(position.fees, dec!(0.0505));
+        assert_eq!(position.funding, dec!(-0.1));
+        assert_eq!(position.unrealized_pnl, dec!(-1));
+        assert_eq!(zilean.account.equity, dec!(998.8495));
+
+        zilean.post_order(test_order("2", OrderSide::Sell, 99.0, 1.0));
+        zilean.match_orders();
+        zilean.account.mark_to_market(&zilean.marks);
+        let position = &zilean.account.position[&key];
+        assert_eq!(position.realized_pnl, dec!(-2));
+        assert_eq!(zilean.account.fees, dec!(0.1));
+        assert_eq!(zilean.account.unrealized_pnl, dec!(0));
+        assert_eq!(zilean.account.equity, dec!(997.8));
+    }
+
+    #[tokio::test]
+    async fn test_set_leverage() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{
+            "balance": {"total": 300, "available": 300, "freezed": 0},
+            "instruments": [{"exchange": "BinanceSwap", "symbol": "BTC_USDT", "tick_size": 0.1, "step_size": 0.001, "max_leverage": 20}]
+        }"#,
+        )
+        .await;
+        let set = |leverage: u32| SetLeverage {
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            contract_type: Some(ContractType::Futures),
+            position_side: PositionSide::Long,
+            leverage,
+        };
+        zilean.post_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        zilean.post_order(test_order("2", OrderSide::Buy, 90.0, 2.0));
+        assert_eq!(zilean.account.balance.get_available(), dec!(19));
+
+        // margin of the position and of the open order at 10x
+        zilean.set_leverage(set(10));
+        assert_eq!(zilean.account.balance.get_available(), dec!(271.9));
+        let key = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        assert_eq!(zilean.account.position[&key].margin_value, dec!(10.1));
+        assert_eq!(zilean.set_leverage(set(0)).message, "Invalid leverage.");
+        // the contract type is the one of the position when missing
+        let response = zilean.set_leverage(SetLeverage { contract_type: None, ..set(10) });
+        assert_eq!(response.status, crate::server::BacktestStatus::Ok);
+        let short = SetLeverage { contract_type: None, position_side: PositionSide::Short, ..set(10) };
+        assert_eq!(zilean.set_leverage(short).message, "Missing contract_type, no position to take it from.");
+        assert_eq!(zilean.set_leverage(set(50)).message, "Leverage 50 is not valid");
+
+        // new orders take the leverage of the position
+        zilean.post_order(test_order("3", OrderSide::Buy, 95.0, 2.0));
+        assert_eq!(zilean.account.balance.get_available(), dec!(252.9));
+        assert_eq!(zilean.set_leverage(set(1)).message, "Insufficient margin.");
+        assert_eq!(zilean.account.balance.get_available(), dec!(252.9));
+
+        // the close gives back the margin at 10x
+        zilean.post_order(test_order("4", OrderSide::Sell, 99.0, 1.0));
+        zilean.match_orders();
+        assert_eq!(zilean.account.balance.get_available(), dec!(261));
+    }
+
+    #[tokio::test]
+    async fn test_adjust_margin_and_transfers() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{
+            "instruments": [{"exchange": "BinanceSwap", "symbol": "BTC_USDT", "tick_size": 0.1, "step_size": 0.001, "maintenance_margin": 0.01}],
+            "transfers": [{"timestamp": 5, "amount": -50}]
+        }"#,
+        )
+        .await;
+        let order = |cid: &str, side: OrderSide, price: f64, amount: f64| Order {
+            leverage: 10,
+            ..test_order(cid, side, price, amount)
+        };
+        let margin = |amount: f64| AdjustMargin {
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            contract_type: Some(ContractType::Futures),
+            position_side: PositionSide::Long,
+            amount,
+        };
+        let key = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        assert_eq!(zilean.adjust_margin(margin(10.0), true).message, "Position not found.");
+        zilean.post_order(order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        assert_eq!(zilean.account.position[&key].liquidation_price, dec!(91.818181818182));
+
+        // more margin moves the liquidation price away
+        zilean.adjust_margin(margin(10.0), true);
+        assert_eq!(zilean.account.balance.get_available(), dec!(979.9));
+        assert_eq!(zilean.account.position[&key].margin_value, dec!(20.1));
+        assert_eq!(zilean.account.position[&key].liquidation_price, dec!(81.717171717172));
+        assert_eq!(zilean.adjust_margin(margin(20.0), false).message, "Insufficient added margin.");
+        // the contract type is the one of the position when missing
+        let response = zilean.adjust_margin(AdjustMargin { contract_type: None, ..margin(1.0) }, true);
+        assert_eq!(response.status, crate::server::BacktestStatus::Ok);
+        zilean.adjust_margin(margin(1.0), false);
+
+        // closing half gives back half of the added margin
+        zilean.post_order(order("2", OrderSide::Sell, 99.0, 0.5));
+        zilean.match_orders();
+        assert_eq!(zilean.account.position[&key].margin_value, dec!(10.05));
+        assert_eq!(zilean.account.balance.get_available(), dec!(988.95));
+
+        zilean.deposit(Transfer { amount: 100.0, ..Default::default() });
+        let response = zilean.withdraw(Transfer { amount: 2000.0, ..Default::default() });
+        assert_eq!(response.message, "Insufficient balance.");
+        zilean.process_transfers(5);
+        assert_eq!(zilean.account.balance.get_available(), dec!(1038.95));
+        assert_eq!(zilean.account.transfers, dec!(50));
+
+        // cross positions have no margin of their own
+        let cross = Order {
+            margin_mode: MarginMode::Cross,
+            position_side: PositionSide::Short,
+            ..order("c", OrderSide::Buy, 99.0, 0.1)
+        };
+        zilean.post_order(cross);
+        zilean.match_orders();
+        let request = AdjustMargin { position_side: PositionSide::Short, ..margin(1.0) };
+        let response = zilean.adjust_margin(request, true);
+        assert_eq!(response.message, "Margin can only be adjusted on isolated positions.");
+        assert_eq!(zilean.account.balance.get_available(), dec!(1037.96));
+    }
+
+    #[tokio::test]
+    async fn test_options_expiry() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{
+            "symbol": "BTC-C-100",
+            "instruments": [{"exchange": "BinanceSwap", "symbol": "BTC-C-100", "tick_size": 0.1, "step_size": 0.01,
+                "option": {"kind": "Call", "strike": 100, "expiry": 10, "margin_rate": 0.2, "settlement_price": 115}}]
+        }"#,
+        )
+        .await;
+        zilean.depth.symbol = "BTC-C-100".to_string();
+        zilean.depth.bids = vec![(9.0, 10.0)];
+        zilean.depth.asks = vec![(10.0, 10.0)];
+        let order = |cid: &str, position_side: PositionSide, price: f64, amount: f64| Order {
+            contract_type: ContractType::Options,
+            symbol: "BTC-C-100".to_string(),
+            position_side,
+            ..test_order(cid, OrderSide::Buy, price, amount)
+        };
+        // long pays the premium, short gets it and freezes 20% of the strike
+        zilean.post_order(order("1", PositionSide::Long, 10.0, 2.0));
+        zilean.post_order(order("2", PositionSide::Short, 9.0, 1.0));
+        zilean.match_orders();
+        assert_eq!(zilean.account.balance.get_available(), dec!(969));
+        zilean.update_marks();
+        zilean.account.mark_to_market(&zilean.marks);
+        assert_eq!(zilean.account.equity, dec!(998.5));
+        // a tick of the underlying doesn't mark the option
+        zilean.depth = Depth {
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            bids: vec![(99.0, 1.0)],
+            asks: vec![(101.0, 1.0)],
+            ..Default::default()
+        };
+        zilean.update_marks();
+        zilean.account.mark_to_market(&zilean.marks);
+        assert_eq!(zilean.account.equity, dec!(998.5));
+        zilean.depth.symbol = "BTC-C-100".to_string();
+
+        // at expiry the resting order is canceled and both sides settle at 15
+        zilean.post_order(order("3", PositionSide::Long, 5.0, 1.0));
+        zilean.settle_expired_options(10);
+        assert_eq!(zilean.account.balance.get_available(), dec!(1004));
+        let key = |side: PositionSide| ("BTC-C-100".to_string(), side, Exchange::BinanceSwap);
+        assert_eq!(zilean.account.position[&key(PositionSide::Long)].realized_pnl, dec!(10));
+        assert_eq!(zilean.account.position[&key(PositionSide::Short)].realized_pnl, dec!(-6));
+        assert_eq!(zilean.account.position[&key(PositionSide::Short)].margin_value, dec!(0));
+        // the settlements are logged as fills
+        let settled: Vec<_> = zilean.fills.iter().filter(|x| x.cid == "BTC-C-100-expiry").map(|x| x.timestamp).collect();
+        assert_eq!(settled, vec![10, 10]);
+        let response = zilean.post_order(order("4", PositionSide::Long, 10.0, 1.0));
+        assert_eq!(response.message, "Option expired.");
+    }
+
+    #[tokio::test]
+    async fn test_dated_futures_delivery() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(
+            r#"{
+            "symbol": "BTC_USDT_240927",
+            "funding": {"rate": 0.01, "interval": 5},
+            "instruments": [{"exchange": "BinanceSwap", "symbol": "BTC_USDT_240927", "tick_size": 0.1, "step_size": 0.001,
+                "expiry": 10, "underlying": "BTC_USDT"}]
+        }"#,
+        )
+        .await;
+        let order = |cid: &str, position_side: PositionSide, price: f64| Order {
+            symbol: "BTC_USDT_240927".to_string(),
+            position_side,
+            leverage: 10,
+            ..test_order(cid, OrderSide::Buy, price, 1.0)
+        };
+        zilean.post_order(order("1", PositionSide::Long, 101.0));
+        zilean.post_order(order("2", PositionSide::Short, 99.0));
+        zilean.match_orders();
+        assert_eq!(zilean.account.balance.get_available(), dec!(980));
+        // no funding on dated futures
+        zilean.marks.insert((Exchange::BinanceSwap, "BTCUSDT240927".to_string()), 100.0);
+        zilean.settle_funding(1);
+        zilean.settle_funding(5);
+        assert_eq!(zilean.account.balance.get_available(), dec!(980));
+
+        // delivered at the index of the underlying
+        zilean.indexes.insert("BTCUSDT".to_string(), 110.0);
+        zilean.deliver_expired_futures(10);
+        assert!(zilean.account.position.is_empty());
+        assert_eq!(zilean.fills.back().map(|x| (x.cid.as_str(), x.timestamp)), Some(("BTC_USDT_240927-delivery", 10)));
+        assert_eq!(zilean.account.balance.get_available(), dec!(998));
+        zilean.account.mark_to_market(&zilean.marks);
+        assert_eq!(zilean.account.realized_pnl, dec!(-2));
+        let response = zilean.post_order(order("3", PositionSide::Long, 101.0));
+        assert_eq!(response.message, "Contract expired.");
+    }
+
+    #[tokio::test]
+    async fn test_close_position() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(r#"{"latency": {"Fixed": 20}}"#).await;
+        zilean.post_order(test_order("1", OrderSide::Buy, 101.0, 2.0));
+        zilean.depth.local_timestamp = 21;
+        zilean.match_orders();
+        zilean.post_order(test_order("2", OrderSide::Sell, 120.0, 2.0));
+        zilean.depth.bids = vec![(99.0, 1.0), (98.0, 5.0)];
+        zilean.reported_state(21);
+        zilean.deliver_tick();
+        // the book computed ahead is not seen by the close
+        zilean.depth.bids = vec![(150.0, 10.0)];
+
+        // the take profit is canceled and the close sweeps the bids without waiting for the latency
+        let request = ClosePosition {
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            ..Default::default()
+        };
+        let response = zilean.close_position(request.clone());
+        assert!(response.message.contains("\"cid\":\"close-BTC_USDT-Long-21\""));
+        assert!(response.message.contains("\"filled_price\":\"98.5\""));
+        let key = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        assert_eq!(zilean.account.position[&key].amount_total, dec!(0));
+        assert_eq!(zilean.account.balance.get_available(), dec!(995));
+        assert_eq!(zilean.close_position(request.clone()).message, "No position to close.");
+
+        // without contract_type the spot holding is left alone
+        zilean.account.holding("BTC_USDT").credit(dec!(3));
+        assert_eq!(zilean.close_position(request.clone()).message, "No position to close.");
+        assert_eq!(zilean.account.holding("BTC_USDT").get_available(), dec!(3));
+        let request = ClosePosition { contract_type: Some(ContractType::Spot), cid: Some("spot".to_string()), ..request };
+        let response = zilean.close_position(request);
+        assert!(response.message.contains("\"filled_price\":\"98\""), "{}", response.message);
+        assert_eq!(zilean.account.holding("BTC_USDT").get_available(), dec!(0));
+    }
+
+    #[tokio::test]
+    async fn test_ipc_close_position() {
+        use crate::market::*;
+        use crate::server::{BacktestResponse, BacktestStatus};
+        let mut zilean = test_engine("{}").await;
+        zilean.post_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        zilean.reported_state(1);
+        zilean.deliver_tick();
+        zilean.account.backtest_id = "bt-close-position".to_string();
+        let tick_url = format!("ipc://{}/zilean-test-{}/", std::env::temp_dir().display(), std::process::id());
+        let url = format!("{}bt-close-position.ipc", tick_url);
+        let client = std::thread::spawn(move || {
+            let socket = zmq::Context::new().socket(zmq::REQ).unwrap();
+            socket.set_rcvtimeo(5000).unwrap();
+            socket.connect(&url).unwrap();
+            let messages = [r#"CLOSE_POSITION{"exchange": "BinanceSwap", "symbol": "BTC_USDT"}"#, "TICK", "CLOSE"];
+            messages
+                .iter()
+                .map(|message| {
+                    socket.send(*message, 0).unwrap();
+                    let reply = socket.recv_string(0).unwrap().unwrap();
+                    sonic_rs::from_str::<BacktestResponse>(&reply).unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
+        // closing a position doesn't end the session, the tick after it is still served
+        zilean.start_listening(&tick_url).await;
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0].status, BacktestStatus::Ok);
+        assert!(replies[0].message.contains("\"filled_price\":\"99\""), "{}", replies[0].message);
+        assert_eq!(replies[1].status, BacktestStatus::Ok);
+        assert_eq!(replies[2].message, "Server closed.");
+    }
+
+    #[tokio::test]
+    async fn test_amend_latency() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(r#"{"latency": {"Fixed": 20}, "cancel_latency": {"Fixed": 5}}"#).await;
+        zilean.post_order(test_order("1", OrderSide::Buy, 90.0, 2.0));
+        zilean.depth.local_timestamp = 21;
+        zilean.match_orders();
+
+        // a new price reaches the exchange after the order latency
+        let amend = AmendOrder { cid: "1".to_string(), price: Some(95.0), amount: None };
+        assert_eq!(zilean.amend_order(amend).message, "cid: 1 amend pending.");
+        zilean.process_pending_cancels(40);
+        assert_eq!(zilean.order_list.get_open_mut("1").map(|x| x.price), Some(90.0));
+        zilean.process_pending_cancels(41);
+        assert_eq!(zilean.order_list.get_open_mut("1").map(|x| (x.price, x.timestamp)), Some((95.0, 41)));
+
+        // a size down waits out the cancel latency, the fills before it win the race
+        zilean.depth.local_timestamp = 41;
+        let amend = AmendOrder { cid: "1".to_string(), price: None, amount: Some(1.0) };
+        assert_eq!(zilean.amend_order(amend).message, "cid: 1 amend pending.");
+        zilean.depth.asks = vec![(94.0, 1.5)];
+        zilean.depth.local_timestamp = 42;
+        zilean.match_orders();
+        assert_eq!(zilean.order_list.get_open_mut("1").map(|x| x.filled_amount), Some(1.5));
+        zilean.process_pending_cancels(46);
+        assert!(zilean.order_list.get_open_mut("1").is_none());
+        assert_eq!(zilean.fills.iter().map(|x| x.filled_amount).sum::<rust_decimal::Decimal>(), dec!(1.5));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        use crate::market::*;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine("{}").await;
+        let order = |cid: &str, position_side: PositionSide, price: f64| Order {
+            position_side,
+            ..test_order(cid, OrderSide::Buy, price, 1.0)
+        };
+        zilean.post_order(order("a", PositionSide::Long, 90.0));
+        zilean.post_order(order("b", PositionSide::Short, 110.0));
+        zilean.post_order(order("c", PositionSide::Long, 91.0));
+        let longs = CancelAll {
+            position_side: Some(PositionSide::Long),
+            ..Default::default()
+        };
+        assert_eq!(zilean.cancel_all(longs).message, r#"["a","c"]"#);
+        assert_eq!(zilean.account.balance.get_available(), dec!(890));
+        assert_eq!(zilean.cancel_all(CancelAll::default()).message, r#"["b"]"#);
+        assert_eq!(zilean.cancel_all(CancelAll::default()).message, "[]");
+        assert_eq!(zilean.account.balance.get_available(), dec!(1000));
+    }
+
+    #[tokio::test]
+    async fn test_batch_rollback() {
+        use crate::market::*;
+        use crate::server::{BacktestResponse, BacktestStatus};
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine("{}").await;
+        zilean.post_order(test_order("a", OrderSide::Buy, 90.0, 1.0));
+        zilean.post_order(test_order("b", OrderSide::Buy, 91.0, 1.0));
+        let ops: Vec<BatchOp> = vec![
+            BatchOp::PostOrder(Box::new(test_order("c", OrderSide::Buy, 92.0, 1.0))),
+            BatchOp::CancelOrder("a".to_string()),
+            BatchOp::AmendOrder(AmendOrder { cid: "b".to_string(), price: Some(93.0), amount: None }),
+        ];
+        let response = zilean.batch(ops);
+        assert_eq!(response.status, BacktestStatus::Ok);
+        let responses: Vec<BacktestResponse> = sonic_rs::from_str(&response.message).unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[1].message, "cid: a order canceled.");
+        assert_eq!(zilean.account.balance.get_available(), dec!(815));
+
+        // the post is rolled back with the failed cancel
+        let ops = vec![BatchOp::PostOrder(Box::new(test_order("d", OrderSide::Buy, 94.0, 1.0))), BatchOp::CancelOrder("x".to_string())];
+        let response = zilean.batch(ops);
+        assert_eq!(response.status, BacktestStatus::Error);
+        let responses: Vec<BacktestResponse> = sonic_rs::from_str(&response.message).unwrap();
+        assert_eq!(responses[1].message, "Order not found");
+        assert!(zilean.order_list.get_open_mut("d").is_none());
+        assert_eq!(zilean.account.balance.get_available(), dec!(815));
+    }
+
+    #[tokio::test]
+    async fn test_step() {
+        use crate::market::*;
+        use crate::server::BacktestStatus;
+        use sonic_rs::JsonValueTrait;
+        let mut zilean = test_engine("{}").await;
+        let order = test_order("a", OrderSide::Buy, 90.0, 1.0);
+        let response = zilean.step(vec![BatchOp::PostOrder(Box::new(order))], r#"{"depth": {"local_timestamp": 2}}"#);
+        assert_eq!(response.status, BacktestStatus::Ok);
+        let step: sonic_rs::Value = sonic_rs::from_str(&response.message).unwrap();
+        assert_eq!(step["results"][0]["status"].as_str(), Some("ok"));
+        assert_eq!(step["tick"]["depth"]["local_timestamp"].as_i64(), Some(2));
+        assert!(zilean.order_list.get_open_mut("a").is_some());
+
+        // the end of the backtest is passed on as the message
+        let response = zilean.step(Vec::new(), "No more data, backtestfinished");
+        let step: sonic_rs::Value = sonic_rs::from_str(&response.message).unwrap();
+        assert_eq!(step["tick"].as_str(), Some("No more data, backtestfinished"));
+
+        let response = zilean.step(vec![BatchOp::CancelOrder("x".to_string())], "{}");
+        assert_eq!(response.status, BacktestStatus::Error);
+    }
+
+    #[tokio::test]
+    async fn test_get_queries() {
+        use crate::market::*;
+        use crate::server::BacktestStatus;
+        use sonic_rs::{JsonContainerTrait, JsonValueTrait};
+        let mut zilean = test_engine(r#"{"fee_rate": {"maker_fee": 0, "taker_fee": 0.0005}}"#).await;
+        zilean.post_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.post_order(test_order("2", OrderSide::Buy, 90.0, 1.0));
+        zilean.match_orders();
+        // marked at the mid of 100
+        zilean.marks.insert((Exchange::BinanceSwap, "BTCUSDT".to_string()), 100.0);
+        zilean.account.mark_to_market(&zilean.marks);
+        // nothing is seen before the tick is delivered
+        zilean.reported_state(1);
+        assert_eq!(zilean.get_open_orders().message, "[]");
+        assert_eq!(zilean.get_fills(FillQuery::default()).message, "[]");
+        zilean.deliver_tick();
+
+        let open: sonic_rs::Value = sonic_rs::from_str(&zilean.get_open_orders().message).unwrap();
+        assert_eq!(open.as_array().map(|x| x.len()), Some(1));
+        assert_eq!(open[0]["cid"].as_str(), Some("2"));
+        let filled: sonic_rs::Value = sonic_rs::from_str(&zilean.get_order("1".to_string()).message).unwrap();
+        assert_eq!(filled["state"].as_str(), Some("Filled"));
+        assert_eq!(zilean.get_order("x".to_string()).status, BacktestStatus::Error);
+
+        let fills: sonic_rs::Value = sonic_rs::from_str(&zilean.get_fills(FillQuery::default()).message).unwrap();
+        assert_eq!(fills.as_array().map(|x| x.len()), Some(1));
+        assert_eq!(fills[0]["cid"].as_str(), Some("1"));
+        assert_eq!(fills[0]["timestamp"].as_i64(), Some(1));
+        assert_eq!(fills[0]["maker"].as_bool(), Some(false));
+        let query = FillQuery { cid: None, since: Some(2) };
+        assert_eq!(zilean.get_fills(query).message, "[]");
+        let query = FillQuery { cid: Some("2".to_string()), since: None };
+        assert_eq!(zilean.get_fills(query).message, "[]");
+        let account: sonic_rs::Value = sonic_rs::from_str(&zilean.get_account().message).unwrap();
+        assert_eq!(account["unrealized_pnl"].as_str(), Some("-1"));
+
+        // the tick computed ahead stays hidden until it is delivered
+        zilean.post_order(test_order("3", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        zilean.reported_state(2);
+        let fills: sonic_rs::Value = sonic_rs::from_str(&zilean.get_fills(FillQuery::default()).message).unwrap();
+        assert_eq!(fills.as_array().map(|x| x.len()), Some(1));
+        assert_eq!(zilean.get_order("3".to_string()).status, BacktestStatus::Error);
+        zilean.deliver_tick();
+        let fills: sonic_rs::Value = sonic_rs::from_str(&zilean.get_fills(FillQuery::default()).message).unwrap();
+        assert_eq!(fills.as_array().map(|x| x.len()), Some(2));
+
+        // an order of the client is seen once posted, its cancel only with the tick that reports it
+        zilean.post_client_order(test_order("4", OrderSide::Buy, 80.0, 1.0));
+        let order: sonic_rs::Value = sonic_rs::from_str(&zilean.get_order("4".to_string()).message).unwrap();
+        assert_eq!(order["state"].as_str(), Some("Open"));
+        let open: sonic_rs::Value = sonic_rs::from_str(&zilean.get_open_orders().message).unwrap();
+        assert_eq!(open.as_array().map(|x| x.len()), Some(2));
+        zilean.cancel_order("4".to_string());
+        let order: sonic_rs::Value = sonic_rs::from_str(&zilean.get_order("4".to_string()).message).unwrap();
+        assert_eq!(order["state"].as_str(), Some("Open"));
+
+        // the log keeps the last fills only
+        for _ in 0..super::FILL_LOG_CAP {
+            zilean.log_fill(&super::FilledStack::default());
+        }
+        zilean.reported_state(3);
+        zilean.deliver_tick();
+        let fills: sonic_rs::Value = sonic_rs::from_str(&zilean.get_fills(FillQuery::default()).message).unwrap();
+        assert_eq!(fills.as_array().map(|x| x.len()), Some(super::FILL_LOG_CAP));
+        assert_eq!(fills[0]["cid"].as_str(), Some(""));
+    }
+
+    #[tokio::test]
+    async fn test_one_way_flip() {
+        use crate::market::*;
+        let mut zilean = test_engine(r#"{"position_mode": "OneWay"}"#).await;
+        zilean.submit_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        // sell 3 with 1 long: close the long, open 2 short
+        let response = zilean.submit_order(test_order("2", OrderSide::Sell, 99.0, 3.0));
+        assert!(response.message.contains("2-open"));
+        zilean.match_orders();
+        let key = |side: PositionSide| ("BTC_USDT".to_string(), side, Exchange::BinanceSwap);
+        assert_eq!(zilean.account.position[&key(PositionSide::Long)].amount_total, rust_decimal_macros::dec!(0));
+        assert_eq!(zilean.account.position[&key(PositionSide::Short)].amount_total, rust_decimal_macros::dec!(2));
+        // reduce only buy closes at most the short
+        let mut reduce = test_order("3", OrderSide::Buy, 101.0, 5.0);
+        reduce.reduce_only = true;
+        zilean.submit_order(reduce);
+        zilean.match_orders();
+        assert_eq!(zilean.account.position[&key(PositionSide::Short)].amount_total, rust_decimal_macros::dec!(0));
+        assert_eq!(zilean.account.position[&key(PositionSide::Long)].amount_total, rust_decimal_macros::dec!(0));
+        // flat after 1 at 101 and 1 at 99, 2 at 99 and 2 at 101
+        assert_eq!(zilean.account.balance.get_available(), rust_decimal_macros::dec!(994));
+    }
+
+    #[tokio::test]
+    async fn test_one_way_split_group() {
+        use crate::market::*;
+        use crate::server::BacktestStatus;
+        use rust_decimal_macros::dec;
+        let mut zilean = test_engine(r#"{"position_mode": "OneWay"}"#).await;
+        zilean.submit_order(test_order("1", OrderSide::Buy, 101.0, 1.0));
+        zilean.match_orders();
+        let long = ("BTC_USDT".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+
+        // a resting sell holds the long, another sell can't open a short next to it
+        zilean.submit_order(test_order("tp", OrderSide::Sell, 120.0, 1.0));
+        let response = zilean.submit_order(test_order("2", OrderSide::Sell, 110.0, 3.0));
+        assert_eq!(response.status, BacktestStatus::Error);
+        assert!(zilean.order_list.get_open_mut("2-open").is_none());
+        zilean.cancel_order("tp".to_string());
+
+        // the open half is not posted, the close half is rolled back
+        let response = zilean.submit_order(test_order("3", OrderSide::Sell, 110.0, 100.0));
+        assert_eq!(response.message, "Insufficient margin.");
+        assert!(zilean.order_list.get_open_mut("3").is_none());
+        assert_eq!(zilean.account.position[&long].amount_available, dec!(1));
+
+        // amends and cancels go to both halves
+        zilean.submit_order(test_order("4", OrderSide::Sell, 110.0, 3.0));
+        let amend = AmendOrder { cid: "4".to_string(), price: Some(111.0), amount: Some(4.0) };
+        assert_eq!(zilean.amend_order(amend).status, BacktestStatus::Ok);
+        assert_eq!(zilean.order_list.get_open_mut("4").map(|x| (x.price, x.amount)), Some((111.0, 1.0)));
+        assert_eq!(zilean.order_list.get_open_mut("4-open").map(|x| (x.price, x.amount)), Some((111.0, 3.0)));
+        zilean.cancel_order("4".to_string());
+        assert!(zilean.order_list.get_open_mut("4-open").is_none());
+        assert_eq!(zilean.account.position[&long].amount_available, dec!(1));
+        assert_eq!(zilean.account.balance.get_available(), dec!(899));
+    }
+
     #[tokio::test]
     async fn test_on_tick() {
         let zconfig = crate::ZConfig::parse("misc/config.toml");
@@ -0,0 +1,167 @@
+use crate::market::*;
+use sonic_rs::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// price impact of our aggressive fills, in bps per unit of amount taken
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImpactModel {
+    #[serde(default)]
+    pub temporary: f64, // decays with half_life
+    #[serde(default)]
+    pub permanent: f64,
+    #[serde(default)]
+    pub half_life: i64, // same unit as local_timestamp
+}
+
+#[derive(Debug, Default, Clone)]
+struct ConsumedLevel {
+    seen: f64, // level amount in the market data when last seen
+    consumed: f64,
+}
+
+// liquidity taken by our aggressive fills per level, until the market data shows it refilled
+#[derive(Debug, Default, Clone)]
+pub struct LiquidityBook {
+    // (exchange, bids, price bits)
+    levels: HashMap<(Exchange, bool, u64), ConsumedLevel>,
+    impact: Option<ImpactModel>,
+    permanent_bps: f64, // signed, buys push up
+    temporary_buy_bps: f64,
+    temporary_sell_bps: f64,
+    last_timestamp: i64,
+}
+
+impl LiquidityBook {
+    pub fn new(impact: Option<ImpactModel>) -> Self {
+        Self {
+            impact,
+            ..Default::default()
+        }
+    }
+
+    // new market data: a level growing pays back what we consumed, a missing level is gone
+    pub fn refresh(&mut self, depth: &Depth) {
+        self.levels.retain(|(exchange, bids, price), level| {
+            if *exchange != depth.exchange {
+                return true;
+            }
+            let price = f64::from_bits(*price);
+            let amount = depth.level_amount(*bids, price);
+            if amount <= 0.0 {
+                return false;
+            }
+            // a shrinking level is other traders, it doesn't refill what we took
+            if amount > level.seen {
+                level.consumed -= amount - level.seen;
+            }
+            level.seen = amount;
+            level.consumed > 1e-9
+        });
+    }
+
+    // depth without the liquidity we already took
+    pub fn available(&self, depth: &Depth) -> Depth {
+        let mut available = depth.clone();
+        if self.levels.is_empty() {
+            return available;
+        }
+        for (bids, levels) in [(true, &mut available.bids), (false, &mut available.asks)] {
+            for level in levels.iter_mut() {
+                if let Some(consumed) = self.levels.get(&(depth.exchange, bids, level.0.to_bits())) {
+                    level.1 = ((level.1 - consumed.consumed) * 1e6).round() / 1e6;
+                }
+            }
+            levels.retain(|level| level.1 > 0.0);
+        }
+        available
+    }
+
+    // impact shift in bps of the side an order takes from
+    pub fn impact_bps(&self, buy: bool, timestamp: i64) -> f64 {
+        let Some(impact) = &self.impact else {
+            return 0.0;
+        };
+        let decay = if impact.half_life > 0 {
+            0.5_f64.powf((timestamp - self.last_timestamp).max(0) as f64 / impact.half_life as f64)
+        } else {
+            0.0
+        };
+        if buy {
+            self.permanent_bps + self.temporary_buy_bps * decay
+        } else {
+            self.permanent_bps - self.temporary_sell_bps * decay
+        }
+    }
+
+    // depth seen by an aggressive order, prices of the side it takes from moved by our past impact
+    pub fn with_impact(&self, depth: &Depth, buy: bool, timestamp: i64) -> Option<Depth> {
+        let shift = self.impact_bps(buy, timestamp);
+        if shift == 0.0 {
+            return None;
+        }
+        let mut shifted = depth.clone();
+        let levels = if buy { &mut shifted.asks } else { &mut shifted.bids };
+        for level in levels.iter_mut() {
+            level.0 *= 1.0 + shift / 1e4;
+        }
+        Some(shifted)
+    }
+
+    pub fn consume(&mut self, exchange: Exchange, bids: bool, price: f64, amount: f64, seen: f64) {
+        let level = self
+            .levels
+            .entry((exchange, bids, price.to_bits()))
+            .or_insert(ConsumedLevel { seen, consumed: 0.0 });
+        level.seen = seen;
+        level.consumed += amount;
+    }
+
+    pub fn record_impact(&mut self, buy: bool, amount: f64, timestamp: i64) {
+        let Some(impact) = self.impact.clone() else {
+            return;
+        };
+        let decay = if impact.half_life > 0 {
+            0.5_f64.powf((timestamp - self.last_timestamp).max(0) as f64 / impact.half_life as f64)
+        } else {
+            0.0
+        };
+        self.temporary_buy_bps *= decay;
+        self.temporary_sell_bps *= decay;
+        if buy {
+            self.permanent_bps += impact.permanent * amount;
+            self.temporary_buy_bps += impact.temporary * amount;
+        } else {
+            self.permanent_bps -= impact.permanent * amount;
+            self.temporary_sell_bps += impact.temporary * amount;
+        }
+        self.last_timestamp = timestamp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liquidity_consumed_until_refilled() {
+        let mut depth = Depth {
+            bids: vec![(99.0, 1.0)],
+            asks: vec![(100.0, 2.0), (101.0, 2.0)],
+            ..Default::default()
+        };
+        let mut book = LiquidityBook::new(None);
+        book.consume(depth.exchange, false, 100.0, 2.0, 2.0);
+        assert_eq!(book.available(&depth).asks, vec![(101.0, 2.0)]);
+        // same snapshot again, the level is still taken
+        book.refresh(&depth);
+        assert_eq!(book.available(&depth).asks, vec![(101.0, 2.0)]);
+        // others take 1 of the rest, nothing is refilled
+        depth.asks[0].1 = 1.0;
+        book.refresh(&depth);
+        assert_eq!(book.available(&depth).asks, vec![(101.0, 2.0)]);
+        // refilled by 1.5
+        depth.asks[0].1 = 2.5;
+        book.refresh(&depth);
+        assert_eq!(book.available(&depth).asks[0], (100.0, 2.0));
+    }
+}
@@ -0,0 +1,272 @@
+use crate::market::*;
+use sonic_rs::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// trading rules of one symbol on one exchange
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InstrumentSpec {
+    pub exchange: Exchange,
+    pub symbol: String,
+    pub tick_size: f64, // price step
+    pub step_size: f64, // quantity step
+    #[serde(default)]
+    pub min_qty: f64,
+    #[serde(default)]
+    pub min_notional: f64,
+    #[serde(default)]
+    pub max_leverage: u32, // 0 means no limit
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64, // contract size, usd per contract for inverse contracts
+    #[serde(default)]
+    pub maintenance_margin: f64, // rate of the notional, for the liquidation price
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub option: Option<OptionSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<i64>, // delivery time of dated futures, perpetual when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_price: Option<f64>, // price of dated futures at expiry
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub underlying: String, // symbol of the index of dated futures
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+// terms of a european option, cash settled against the index at expiry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OptionSpec {
+    pub kind: OptionKind,
+    pub strike: f64,
+    pub expiry: i64, // same unit as local_timestamp
+    #[serde(default)]
+    pub underlying: String, // symbol of the index in the market data
+    #[serde(default = "default_option_margin")]
+    pub margin_rate: f64, // margin of short options, rate of the strike
+    #[serde(default)]
+    pub settlement_price: Option<f64>, // index at expiry, the last mid of the underlying when missing
+}
+
+fn default_option_margin() -> f64 {
+    0.15
+}
+
+impl OptionSpec {
+    // payoff of one unit at the index
+    pub fn intrinsic(&self, index: f64) -> f64 {
+        match self.kind {
+            OptionKind::Call => (index - self.strike).max(0.0),
+            OptionKind::Put => (self.strike - index).max(0.0),
+        }
+    }
+}
+
+// rule an order broke, turned into the message of its venue
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reject {
+    PriceTick,
+    LotSize,
+    MinQty,
+    MinNotional,
+    Leverage,
+}
+
+impl Reject {
+    pub fn message(&self, exchange: Exchange, spec: &InstrumentSpec, leverage: u32) -> String {
+        match exchange {
+            Exchange::BinanceSpot => match self {
+                Reject::PriceTick => "Filter failure: PRICE_FILTER".to_string(),
+                Reject::LotSize | Reject::MinQty => "Filter failure: LOT_SIZE".to_string(),
+                Reject::MinNotional => "Filter failure: NOTIONAL".to_string(),
+                Reject::Leverage => format!("Leverage {} is not valid", leverage),
+            },
+            Exchange::BinanceSwap => match self {
+                Reject::PriceTick => "Filter failure: PRICE_FILTER".to_string(),
+                Reject::LotSize | Reject::MinQty => "Filter failure: LOT_SIZE".to_string(),
+                Reject::MinNotional => format!(
+                    "Order's notional must be no smaller than {} (unless you choose reduce only).",
+                    spec.min_notional
+                ),
+                Reject::Leverage => format!("Leverage {} is not valid", leverage),
+            },
+            Exchange::OkxSpot | Exchange::OkxSwap => match self {
+                Reject::PriceTick => "51000: Parameter px error".to_string(),
+                Reject::LotSize => "51121: Order quantity must be a multiple of the lot size.".to_string(),
+                Reject::MinQty | Reject::MinNotional => {
+                    "51020: Your order should meet or exceed the minimum order amount.".to_string()
+                }
+                Reject::Leverage => "51000: Parameter lever error".to_string(),
+            },
+            Exchange::BybitSwap => match self {
+                Reject::PriceTick => "10001: Order price has too many decimals.".to_string(),
+                Reject::LotSize => "10001: Qty invalid".to_string(),
+                Reject::MinQty => "170136: Order quantity exceeded lower limit.".to_string(),
+                Reject::MinNotional => "170140: Order value exceeded lower limit.".to_string(),
+                Reject::Leverage => "10001: leverage invalid".to_string(),
+            },
+            _ => match self {
+                Reject::PriceTick => format!("Invalid price, tick size is {}.", spec.tick_size),
+                Reject::LotSize => format!("Invalid amount, step size is {}.", spec.step_size),
+                Reject::MinQty => format!("Invalid amount, minimum is {}.", spec.min_qty),
+                Reject::MinNotional => format!("Invalid notional, minimum is {}.", spec.min_notional),
+                Reject::Leverage => format!("Invalid leverage, maximum is {}.", spec.max_leverage),
+            },
+        }
+    }
+}
+
+// value is a whole number of steps
+fn on_step(value: f64, step: f64) -> bool {
+    if step <= 0.0 {
+        return true;
+    }
+    let steps = value / step;
+    (steps - steps.round()).abs() < 1e-9 * steps.abs().max(1.0)
+}
+
+impl InstrumentSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.tick_size > 0.0 && self.step_size > 0.0 && self.multiplier > 0.0)
+            || self.min_qty < 0.0
+            || self.min_notional < 0.0
+            || !(0.0..1.0).contains(&self.maintenance_margin)
+        {
+            return Err(format!("Invalid instrument: {:?}", self));
+        }
+        if self.delivery_price.is_some_and(|x| !x.is_finite() || x <= 0.0) {
+            return Err(format!("Invalid delivery price: {:?}", self));
+        }
+        if let Some(option) = &self.option {
+            if !(option.strike > 0.0 && option.margin_rate.is_finite() && option.margin_rate >= 0.0)
+                || option.settlement_price.is_some_and(|x| !x.is_finite() || x < 0.0)
+            {
+                return Err(format!("Invalid option: {:?}", option));
+            }
+        }
+        Ok(())
+    }
+
+    // notional in the quote currency, in usd for inverse contracts
+    pub fn notional(&self, contract_type: &ContractType, price: f64, amount: f64) -> f64 {
+        match contract_type {
+            ContractType::Inverse => amount * self.multiplier,
+            _ => price * amount * self.multiplier,
+        }
+    }
+
+    pub fn check(&self, contract_type: &ContractType, price: f64, amount: f64, leverage: u32) -> Result<(), Reject> {
+        if !on_step(price, self.tick_size) {
+            return Err(Reject::PriceTick);
+        }
+        if !on_step(amount, self.step_size) {
+            return Err(Reject::LotSize);
+        }
+        if amount < self.min_qty {
+            return Err(Reject::MinQty);
+        }
+        if self.notional(contract_type, price, amount) < self.min_notional {
+            return Err(Reject::MinNotional);
+        }
+        if self.max_leverage > 0 && leverage > self.max_leverage {
+            return Err(Reject::Leverage);
+        }
+        Ok(())
+    }
+
+    // amount rounded down to a whole number of steps
+    pub fn floor_amount(&self, amount: f64) -> f64 {
+        let steps = (amount / self.step_size * (1.0 + 1e-12)).floor();
+        (steps * self.step_size * 1e12).round() / 1e12
+    }
+
+    // venue message of the first rule the order breaks
+    pub fn check_order(&self, order: &Order) -> Result<(), String> {
+        let leverage = if order.contract_type == ContractType::Spot { 1 } else { order.leverage };
+        self.check(&order.contract_type, order.price, order.amount, leverage)
+            .map_err(|reject| reject.message(order.exchange, self, leverage))
+    }
+}
+
+// instrument specs by (exchange, symbol)
+#[derive(Debug, Default, Clone)]
+pub struct Instruments {
+    inner: HashMap<(Exchange, String), InstrumentSpec>,
+}
+
+impl Instruments {
+    // specs of the file, then the ones of the backtest config on top
+    pub fn load(path: Option<&str>, specs: &[InstrumentSpec]) -> Result<Self, String> {
+        let mut instruments = Instruments::default();
+        if let Some(path) = path {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read instruments {}: {}", path, e))?;
+            let file_specs: Vec<InstrumentSpec> = sonic_rs::from_str(&content)
+                .map_err(|e| format!("Failed to parse instruments {}: {}", path, e))?;
+            instruments.extend(file_specs)?;
+        }
+        instruments.extend(specs.iter().cloned())?;
+        Ok(instruments)
+    }
+
+    fn extend(&mut self, specs: impl IntoIterator<Item = InstrumentSpec>) -> Result<(), String> {
+        for spec in specs {
+            spec.validate()?;
+            self.inner.insert((spec.exchange, spec.symbol.clone()), spec);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, exchange: Exchange, symbol: &str) -> Option<&InstrumentSpec> {
+        self.inner.get(&(exchange, symbol.to_string()))
+    }
+
+    pub fn options(&self) -> impl Iterator<Item = &InstrumentSpec> {
+        self.inner.values().filter(|spec| spec.option.is_some())
+    }
+
+    // dated futures
+    pub fn dated(&self) -> impl Iterator<Item = &InstrumentSpec> {
+        self.inner.values().filter(|spec| spec.option.is_none() && spec.expiry.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instrument_check() {
+        let spec = InstrumentSpec {
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USDT".to_string(),
+            tick_size: 0.1,
+            step_size: 0.001,
+            min_qty: 0.001,
+            min_notional: 100.0,
+            max_leverage: 125,
+            multiplier: 1.0,
+            maintenance_margin: 0.004,
+            option: None,
+            expiry: None,
+            delivery_price: None,
+            underlying: String::new(),
+        };
+        spec.validate().unwrap();
+        assert_eq!(spec.check(&ContractType::Futures, 60000.1, 0.002, 10), Ok(()));
+        assert_eq!(spec.check(&ContractType::Futures, 60000.15, 0.002, 10), Err(Reject::PriceTick));
+        assert_eq!(spec.check(&ContractType::Futures, 60000.1, 0.0025, 10), Err(Reject::LotSize));
+        assert_eq!(spec.check(&ContractType::Futures, 60000.0, 0.001, 10), Err(Reject::MinNotional));
+        assert_eq!(spec.check(&ContractType::Futures, 60000.0, 0.002, 150), Err(Reject::Leverage));
+        let order = Order {
+            contract_type: ContractType::Futures,
+            exchange: Exchange::BinanceSwap,
+            price: 60000.0,
+            amount: 0.0025,
+            leverage: 10,
+            ..Default::default()
+        };
+        assert_eq!(spec.check_order(&order), Err("Filter failure: LOT_SIZE".to_string()));
+        assert_eq!(spec.floor_amount(0.0025), 0.002);
+        assert_eq!(spec.floor_amount(0.003), 0.003);
+    }
+}
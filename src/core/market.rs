@@ -1,6 +1,9 @@
 use crate::engine::*;
+use crate::queue::{QueueLevel, QueueModel};
+use crate::round::round6;
 use clickhouse::Row;
 use log::info;
+use rand::RngCore;
 use serde::ser::Serializer;
 use sonic_rs::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -55,23 +58,134 @@ pub enum ContractType {
     #[default]
     Spot, // 现货
     Futures,    // 期货(永续合约)
-    // Options,    // 期权
+    Inverse,    // 币本位合约, margin and pnl in the base coin, amount in contracts of usd
+    Options,    // 期权, european, premium paid by longs, cash settled at expiry
     // Perpetual, // 永续合约
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum OptionKind {
+    #[default]
+    Call, // 看涨
+    Put,  // 看跌
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub enum MarginMode {
     Cross, // 全仓
     #[default]
     Isolated, // 逐仓
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PositionMode {
+    #[default]
+    Hedge, // 双向持仓, side opens or closes the position_side
+    OneWay, // 单向持仓, side is the direction, one net position per symbol
+}
+
 // 用于平仓
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct ClosePosition {
+    pub exchange: Exchange,
+    pub symbol: String,
+    #[serde(default)]
+    pub contract_type: Option<ContractType>, // the one of the position, required to sell spot holdings
+    #[serde(default)]
+    pub position_side: PositionSide,
+    #[serde(default)]
+    pub price: Option<f64>, // aggressive limit price, market when missing
+    #[serde(default)]
+    pub cid: Option<String>,
+}
+
+// 用于改单
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AmendOrder {
+    pub cid: String,
+    #[serde(default)]
+    pub price: Option<f64>,
+    #[serde(default)]
+    pub amount: Option<f64>,
+}
+
+// 批量操作, {"PostOrder": {...}}, {"CancelOrder": "cid"} or {"AmendOrder": {...}}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum BatchOp {
+    PostOrder(Box<Order>),
+    CancelOrder(String),
+    AmendOrder(AmendOrder),
+}
+
+// 批量撤单, a missing filter matches every order
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct CancelAll {
+    #[serde(default)]
+    pub exchange: Option<Exchange>,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub side: Option<OrderSide>,
+    #[serde(default)]
+    pub position_side: Option<PositionSide>,
+}
+
+impl CancelAll {
+    pub fn matches(&self, order: &Order) -> bool {
+        self.exchange.is_none_or(|x| x == order.exchange)
+            && self.symbol.as_ref().is_none_or(|x| *x == order.symbol)
+            && self.side.as_ref().is_none_or(|x| *x == order.side)
+            && self.position_side.as_ref().is_none_or(|x| *x == order.position_side)
+    }
+}
+
+// 成交查询, the fills of an order and/or the ones since a timestamp
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct FillQuery {
+    #[serde(default)]
+    pub cid: Option<String>,
+    #[serde(default)]
+    pub since: Option<i64>,
+}
+
+impl FillQuery {
+    pub fn matches(&self, filled: &FilledStack) -> bool {
+        self.cid.as_ref().is_none_or(|x| *x == filled.cid) && self.since.is_none_or(|x| filled.timestamp >= x)
+    }
+}
+
+// 用于调整杠杆
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SetLeverage {
+    pub exchange: Exchange,
+    pub symbol: String,
+    #[serde(default)]
+    pub contract_type: Option<ContractType>, // the one of the position when missing
+    #[serde(default)]
+    pub position_side: PositionSide,
+    pub leverage: u32,
+}
+
+// 追加/减少逐仓保证金
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AdjustMargin {
+    pub exchange: Exchange,
     pub symbol: String,
+    #[serde(default)]
+    pub contract_type: Option<ContractType>, // the one of the position when missing
+    #[serde(default)]
+    pub position_side: PositionSide,
+    pub amount: f64,
+}
+
+// 充值/提现, amount is negative for withdrawals of the config
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    #[serde(default)]
+    pub timestamp: i64, // scheduled transfers apply when the backtest reaches it
+    #[serde(default)]
+    pub currency: String, // currency of balance when empty
     pub amount: f64,
-    pub price: f64,
-    pub side: OrderSide,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -101,8 +215,68 @@ impl Balance {
         match filled.contract_type {
             ContractType::Spot => self.fill_freezed_spot(filled),
             ContractType::Futures => self.fill_freezed_futures(filled),
+            ContractType::Inverse => self.fill_freezed_inverse(filled),
+            ContractType::Options => self.fill_freezed_options(filled),
         }
     }
+    // premium of the fill, short opens also move the margin of the order to the position
+    pub fn fill_freezed_options(&mut self, filled: &FilledStack) {
+        let amount = filled.filled_amount.abs();
+        let premium = (filled.filled_price * amount * filled.multiplier).round_dp(12);
+        let open = (filled.side == PositionSide::Long) == (filled.filled_amount > dec!(0));
+        match (&filled.side, open) {
+            (PositionSide::Long, true) => {
+                let freeze = (filled.post_price * amount * filled.multiplier).round_dp(12);
+                self.total -= premium;
+                self.freezed -= freeze;
+                self.available += freeze - premium;
+            }
+            (PositionSide::Long, false) => {
+                self.total += premium;
+                self.available += premium;
+            }
+            (PositionSide::Short, true) => {
+                let margin = filled.margin_share();
+                self.total += premium - margin;
+                self.freezed -= margin;
+                self.available += premium;
+            }
+            (PositionSide::Short, false) => {
+                self.total -= premium;
+                self.available -= premium;
+            }
+        }
+        self.round();
+    }
+    // same as linear futures, with values in coin: contracts * contract size / price
+    pub fn fill_freezed_inverse(&mut self, filled: &FilledStack) {
+        let amount = filled.filled_amount.abs();
+        let size = amount * filled.multiplier;
+        let leverage = Decimal::from_u32(filled.leverage).unwrap_or(dec!(1));
+        let coin_value = |price: Decimal| {
+            if price > dec!(0) {
+                (size / price / leverage).round_dp(12)
+            } else {
+                dec!(0)
+            }
+        };
+        let open_price = filled.open_price.unwrap_or(filled.filled_price);
+        // open long / open short
+        let open = (filled.side == PositionSide::Long) == (filled.filled_amount > dec!(0));
+        if open {
+            let value = coin_value(filled.filled_price);
+            let freeze = coin_value(filled.post_price);
+            self.total -= value;
+            self.freezed -= freeze;
+            self.available += freeze - value;
+        } else if open_price > dec!(0) && filled.filled_price > dec!(0) {
+            let value_open = coin_value(open_price);
+            let pnl = filled.realized_pnl();
+            self.total += value_open + pnl;
+            self.available += value_open + pnl;
+        }
+        self.round();
+    }
     pub fn fill_freezed_futures(&mut self, filled: &FilledStack) {
         info!("bal filled: {:?}, before filled: t{}, a{}, f{}", filled, self.total, self.available, self.freezed);
         let amount = filled.filled_amount;
@@ -124,8 +298,9 @@ impl Balance {
                 self.total -= value_open ;
                 self.available -= value_open ;
                 
-                self.total += amount * (filled.filled_price - filled.open_price.unwrap_or(filled.filled_price));
-                self.available += amount * (filled.filled_price - filled.open_price.unwrap_or(filled.filled_price));
+                // amount < 0, pnl is -amount * (fill - open)
+                self.total -= amount * (filled.filled_price - filled.open_price.unwrap_or(filled.filled_price));
+                self.available -= amount * (filled.filled_price - filled.open_price.unwrap_or(filled.filled_price));
                 // println!("value: {}, freeze: {}", value * (filled.leverage - 1) as f64, value * filled.leverage as f64);
             }
         } else if filled.side == PositionSide::Short {
@@ -165,6 +340,32 @@ impl Balance {
         }
     }
 
+    // fees and funding, negative is a rebate
+    pub fn charge(&mut self, value: Decimal) {
+        self.total -= value;
+        self.available -= value;
+        self.round();
+    }
+
+    // margin given back by a position or a deposit, negative debits
+    pub fn credit(&mut self, value: Decimal) {
+        self.total += value;
+        self.available += value;
+        self.round();
+    }
+
+    // base currency side of a spot fill, sells were freezed when posted
+    pub fn fill_holding(&mut self, filled: &FilledStack) {
+        let amount = filled.filled_amount;
+        self.total += amount;
+        if amount > dec!(0) {
+            self.available += amount;
+        } else {
+            self.freezed += amount;
+        }
+        self.round();
+    }
+
     pub fn round(&mut self) {
         self.freezed = self.freezed.round_dp(12);
         self.available = self.available.round_dp(12);
@@ -178,6 +379,23 @@ pub struct FeeRate {
     taker_fee: f64,
 }
 
+impl FeeRate {
+    pub fn rate(&self, maker: bool) -> f64 {
+        if maker {
+            self.maker_fee
+        } else {
+            self.taker_fee
+        }
+    }
+}
+
+// 资金费率, paid by longs to shorts every interval when positive
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Funding {
+    pub rate: f64,
+    pub interval: i64, // same unit as local_timestamp
+}
+
 // version1.0 only support spot position, so no position side
 #[derive(Debug, Default, Serialize, Clone, Hash, Eq, PartialEq, Deserialize)]
 pub enum PositionSide {
@@ -192,6 +410,130 @@ pub struct Account {
     pub balance: Balance,
     #[serde(serialize_with = "serialize_position_map")]
     pub position: HashMap<(String, PositionSide, Exchange), Position>, // String: Symbol_ContractType
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub wallet: HashMap<String, Balance>, // spot holdings and inverse margin by currency
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub currency: String, // currency of balance
+    // in the currency of balance, inverse positions report theirs in coin
+    pub realized_pnl: Decimal,
+    pub unrealized_pnl: Decimal,
+    pub fees: Decimal, // all fees, spot included
+    pub funding: Decimal,
+    pub equity: Decimal, // balance, margin and unrealized pnl of futures, spot holdings at mark price
+    pub transfers: Decimal, // net deposits of balance
+    #[serde(skip)]
+    pub delivered_pnl: Decimal, // realized pnl of the positions removed at delivery
+    #[serde(skip)]
+    pub delivered_funding: Decimal,
+}
+
+impl Account {
+    // balance of a currency, the main balance for its own currency
+    pub fn currency_balance(&mut self, currency: &str) -> &mut Balance {
+        if currency.is_empty() || currency == self.currency {
+            return &mut self.balance;
+        }
+        self.wallet.entry(currency.to_string()).or_default()
+    }
+
+    // balance a buy of the symbol freezes and settles against: the quote for spot, the base coin for inverse
+    pub fn margin_balance(&mut self, contract_type: &ContractType, symbol: &str) -> &mut Balance {
+        let (base, quote) = split_symbol(symbol);
+        match contract_type {
+            ContractType::Spot => self.currency_balance(&quote),
+            ContractType::Inverse => self.currency_balance(&base),
+            ContractType::Futures | ContractType::Options => &mut self.balance,
+        }
+    }
+
+    // spot holdings of the base currency, freezed by sells
+    pub fn holding(&mut self, symbol: &str) -> &mut Balance {
+        self.currency_balance(&split_symbol(symbol).0)
+    }
+
+    // settle a fill on the balances, spot fills move both the quote and the base currency
+    pub fn fill_freezed(&mut self, filled: &FilledStack) {
+        let balance = self.margin_balance(&filled.contract_type, &filled.symbol);
+        balance.fill_freezed(filled);
+        balance.charge(filled.fee);
+        if filled.contract_type == ContractType::Spot {
+            self.holding(&filled.symbol).fill_holding(filled);
+        }
+        if filled.contract_type != ContractType::Inverse {
+            self.fees += filled.fee;
+        }
+    }
+
+    // unrealized pnl of the positions and equity at the mark prices of each symbol
+    pub fn mark_to_market(&mut self, marks: &HashMap<(Exchange, String), f64>) {
+        let mut unrealized = dec!(0);
+        let mut realized = dec!(0);
+        let mut funding = dec!(0);
+        let mut margin = dec!(0);
+        let mut spot_unrealized = dec!(0);
+        // margin and unrealized pnl of inverse positions, in coin out of the wallet
+        let mut coin_positions: HashMap<String, Decimal> = HashMap::new();
+        for ((symbol, _, exchange), position) in self.position.iter_mut() {
+            let Some(mark) = marks.get(&(*exchange, index_key(symbol))).and_then(|x| Decimal::from_f64(*x)) else {
+                continue;
+            };
+            position.mark(mark);
+            if position.contract_type == ContractType::Spot {
+                // the holdings are valued from the wallet
+                spot_unrealized += position.unrealized_pnl;
+                realized += position.realized_pnl;
+            } else if position.contract_type == ContractType::Inverse {
+                *coin_positions.entry(split_symbol(symbol).0).or_default() +=
+                    position.margin_value + position.unrealized_pnl;
+            } else {
+                // linear, inverse ones are in coin
+                unrealized += position.unrealized_pnl;
+                realized += position.realized_pnl;
+                funding += position.funding;
+                margin += position.margin_value;
+                if position.contract_type == ContractType::Options && position.side == PositionSide::Short {
+                    // the premium received is in the balance until bought back
+                    margin -= position.amount_total * position.multiplier * position.entry_price;
+                }
+            }
+        }
+        // every currency of the wallet at the mark of its symbol quoted in the balance currency
+        let mut holdings = dec!(0);
+        let mut currencies: Vec<&String> = self.wallet.keys().collect();
+        currencies.extend(coin_positions.keys().filter(|x| !self.wallet.contains_key(*x)));
+        for currency in currencies {
+            let mark = marks.iter().find_map(|((_, symbol), mark)| {
+                let (base, quote) = split_symbol(symbol);
+                (base == *currency && quote == self.currency).then_some(*mark)
+            });
+            if let Some(mark) = mark.and_then(Decimal::from_f64) {
+                let total = self.wallet.get(currency).map_or(dec!(0), |x| x.total);
+                holdings += (total + coin_positions.get(currency).copied().unwrap_or(dec!(0))) * mark;
+            }
+        }
+        self.unrealized_pnl = (unrealized + spot_unrealized).round_dp(12);
+        self.realized_pnl = (realized + self.delivered_pnl).round_dp(12);
+        self.funding = (funding + self.delivered_funding).round_dp(12);
+        self.equity = (self.balance.total + margin + unrealized + holdings).round_dp(12);
+    }
+}
+
+// (base, quote) of BTC_USDT, BTC-USD, BTC/USDT or BTCUSDT
+pub fn split_symbol(symbol: &str) -> (String, String) {
+    let symbol = symbol.to_uppercase();
+    if let Some((base, quote)) = symbol.split_once(['_', '-', '/']) {
+        // BTC-USD-SWAP
+        let quote = quote.split(['_', '-', '/']).next().unwrap_or(quote);
+        return (base.to_string(), quote.to_string());
+    }
+    for quote in ["USDT", "USDC", "FDUSD", "BUSD", "USD", "BTC", "ETH"] {
+        if let Some(base) = symbol.strip_suffix(quote) {
+            if !base.is_empty() {
+                return (base.to_string(), quote.to_string());
+            }
+        }
+    }
+    (symbol, String::new())
 }
 fn serialize_position_map<S>(
     position_map: &HashMap<(String, PositionSide, Exchange), Position>, 
@@ -226,19 +568,99 @@ pub struct Position {
     pub amount_available: Decimal,
     pub amount_freezed: Decimal,
     pub entry_price: Decimal,
+    pub realized_pnl: Decimal,
+    pub unrealized_pnl: Decimal, // at the last mark price
+    pub fees: Decimal,
+    pub funding: Decimal, // received, negative when paid
+    pub added_margin: Decimal, // by ADD_MARGIN, part of margin_value
+    pub liquidation_price: Decimal, // 0 when the margin covers any price
+    pub margin_mode: MarginMode, // of the order that opened the position
+    #[serde(skip)]
+    pub multiplier: Decimal, // contract size of inverse and option positions, 0 for linear ones
+    #[serde(skip)]
+    pub contract_type: ContractType,
     // entry_time: i64,
-    pub stop_loss: Vec<(Decimal, Decimal)>,
 }
 
 impl Position {
+    pub fn mark(&mut self, mark: Decimal) {
+        self.unrealized_pnl = if self.amount_total == dec!(0) || self.entry_price == dec!(0) || mark <= dec!(0) {
+            dec!(0)
+        } else if self.contract_type == ContractType::Inverse {
+            self.amount_total * self.multiplier * (dec!(1) / self.entry_price - dec!(1) / mark)
+        } else if self.contract_type == ContractType::Options {
+            self.amount_total * self.multiplier * (mark - self.entry_price)
+        } else {
+            self.amount_total * (mark - self.entry_price)
+        };
+        if self.side == PositionSide::Short {
+            self.unrealized_pnl = -self.unrealized_pnl;
+        }
+        self.unrealized_pnl = self.unrealized_pnl.round_dp(12);
+    }
+
+    // notional at the mark price, in coin for inverse positions
+    pub fn notional(&self, mark: Decimal) -> Decimal {
+        if self.contract_type == ContractType::Inverse {
+            if mark > dec!(0) {
+                self.amount_total * self.multiplier / mark
+            } else {
+                dec!(0)
+            }
+        } else if self.contract_type == ContractType::Options {
+            self.amount_total * self.multiplier * mark
+        } else {
+            self.amount_total * mark
+        }
+    }
+
     pub fn update_pos(&mut self, filled : &FilledStack) {
         self.round();
+        self.realized_pnl += filled.realized_pnl();
+        self.fees += filled.fee;
+        self.contract_type = filled.contract_type.clone();
+        if self.amount_total == dec!(0) {
+            self.margin_mode = filled.margin_mode.clone();
+        }
+        if filled.contract_type == ContractType::Inverse || filled.contract_type == ContractType::Options {
+            self.multiplier = filled.multiplier;
+        }
         info!("filled: {:?}, before filled: t{}, a{}, f{}", filled.cid, self.amount_total, self.amount_available, self.amount_freezed);
         let price = filled.filled_price;
         let amount = filled.filled_amount;
         let old_value = self.amount_total * self.entry_price;
 
-        if filled.contract_type == ContractType::Futures {
+        if filled.contract_type == ContractType::Inverse {
+            // entry price of inverse contracts is the harmonic mean of the fill prices
+            let old_total = self.amount_total;
+            let opened = (filled.side == PositionSide::Long) == (amount > dec!(0));
+            if filled.side == PositionSide::Long {
+                self.amount_total += amount;
+            } else {
+                self.amount_total -= amount;
+            }
+            if opened {
+                self.amount_available += amount.abs();
+                if self.entry_price > dec!(0) && price > dec!(0) {
+                    self.entry_price = self.amount_total / (old_total / self.entry_price + amount.abs() / price);
+                } else {
+                    self.entry_price = price;
+                }
+            } else {
+                self.amount_freezed -= amount.abs();
+            }
+            if self.amount_total == dec!(0) {
+                self.entry_price = dec!(0);
+                self.margin_value = dec!(0);
+            } else {
+                // in coin
+                self.margin_value = (self.amount_total * filled.multiplier
+                    / self.entry_price
+                    / Decimal::from_u32(filled.leverage).unwrap_or(dec!(1)))
+                .round_dp(12)
+                    + self.added_margin;
+            }
+        } else if filled.contract_type == ContractType::Futures {
             if filled.side == PositionSide::Long {
                 // open long:amount>0 / close long: amount<0
                 self.amount_total += amount;
@@ -270,24 +692,93 @@ impl Position {
                 self.entry_price = dec!(0);
             }
             
-            self.margin_value = self.amount_total * self.entry_price / Decimal::from_u32(filled.leverage).unwrap_or(dec!(1));
-        }else if filled.contract_type == ContractType::Spot {
-            self.amount_total += amount;
-            if amount > dec!(0) {
-                self.amount_available += amount;
+            self.margin_value = self.amount_total * self.entry_price / Decimal::from_u32(filled.leverage).unwrap_or(dec!(1))
+                + self.added_margin;
+        } else if filled.contract_type == ContractType::Options {
+            // amounts of both sides are positive, longs hold the premium paid as margin
+            let opened = (filled.side == PositionSide::Long) == (amount > dec!(0));
+            let old_total = self.amount_total;
+            if opened {
+                self.amount_total += amount.abs();
+                self.amount_available += amount.abs();
+                self.entry_price = (old_value + amount.abs() * price) / self.amount_total;
+                if filled.side == PositionSide::Short {
+                    self.margin_value += filled.margin_share();
+                }
             } else {
-                self.amount_freezed += amount;
+                self.amount_total -= amount.abs();
+                self.amount_freezed -= amount.abs();
+                if filled.side == PositionSide::Short && old_total > dec!(0) {
+                    self.margin_value = (self.margin_value * self.amount_total / old_total).round_dp(12);
+                }
             }
-
             if self.amount_total == dec!(0) {
                 self.entry_price = dec!(0);
-            } else {
+                self.margin_value = dec!(0);
+            } else if filled.side == PositionSide::Long {
+                self.margin_value = (self.amount_total * self.entry_price * filled.multiplier).round_dp(12);
+            }
+        }else if filled.contract_type == ContractType::Spot {
+            // cost basis of what was bought in the backtest, the holding and its freezes are in the wallet
+            if amount > dec!(0) {
+                self.amount_total += amount;
                 self.entry_price = (old_value + amount * price) / self.amount_total;
+            } else {
+                // holdings of the initial wallet have no entry price
+                let sold = (-amount).min(self.amount_total);
+                self.realized_pnl += (sold * (price - self.entry_price)).round_dp(12);
+                self.amount_total -= sold;
+                if self.amount_total == dec!(0) {
+                    self.entry_price = dec!(0);
+                }
             }
+            self.amount_available = self.amount_total;
         }
         info!("pos filled: {:?}, after filled: t{}, a{}, f{}", filled.cid, self.amount_total, self.amount_available, self.amount_freezed);
     }
 
+    // share of the added margin a closing fill gives back, before the position is updated
+    pub fn release_added_margin(&mut self, filled: &FilledStack) -> Decimal {
+        let closing = (filled.side == PositionSide::Long) == (filled.filled_amount < dec!(0));
+        if !closing || self.added_margin == dec!(0) || self.amount_total == dec!(0) {
+            return dec!(0);
+        }
+        let released = (self.added_margin * (filled.filled_amount.abs() / self.amount_total.abs()).min(dec!(1))).round_dp(12);
+        self.added_margin -= released;
+        released
+    }
+
+    // price where the margin left is the maintenance margin, with the rate of the notional
+    pub fn update_liquidation_price(&mut self, maintenance: Decimal) {
+        let amount = self.amount_total.abs();
+        self.liquidation_price = if amount == dec!(0) || self.entry_price == dec!(0) || self.contract_type == ContractType::Options {
+            dec!(0)
+        } else if self.contract_type == ContractType::Inverse {
+            // inverse, margin in coin
+            let size = amount * self.multiplier;
+            let (numerator, denominator) = match self.side {
+                PositionSide::Long => (size * (dec!(1) + maintenance), size / self.entry_price + self.margin_value),
+                PositionSide::Short => (size * (dec!(1) - maintenance), size / self.entry_price - self.margin_value),
+            };
+            if denominator > dec!(0) {
+                numerator / denominator
+            } else {
+                dec!(0)
+            }
+        } else {
+            match self.side {
+                PositionSide::Long => {
+                    (amount * self.entry_price - self.margin_value) / (amount * (dec!(1) - maintenance))
+                }
+                PositionSide::Short => {
+                    (amount * self.entry_price + self.margin_value) / (amount * (dec!(1) + maintenance))
+                }
+            }
+            .max(dec!(0))
+        };
+        self.liquidation_price = self.liquidation_price.round_dp(12);
+    }
+
     pub fn add_freezed(&mut self, value: Decimal) {
         self.amount_freezed += value;
         self.amount_available -= value;
@@ -330,6 +821,10 @@ pub struct Order {
     pub take_profit: Option<f64>, // Take profit price
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop_loss: Option<f64>, // Stop loss price
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_price: Option<f64>, // trigger price, the order rests untriggered until mid price crosses it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oco_group: Option<String>, // a fill on one order of the group takes its amount off the others, a full fill cancels them
     #[serde(default, skip_serializing)]
     pub reduce_only: bool, // default false
 
@@ -344,10 +839,24 @@ pub struct Order {
     pub amount: f64,
     #[serde(default)]
     pub filled_amount: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_amount: Option<f64>, // iceberg: visible size, refilled after it's fully filled
+    #[serde(skip)]
+    pub display_filled: f64,
+    #[serde(skip)]
+    pub multiplier: f64, // contract size of the instrument, set when posted
+    #[serde(skip)]
+    pub option_margin: f64, // margin per contract of short options, set when posted
+    #[serde(skip)]
+    pub one_way: bool, // stop order of one-way mode, netted when triggered
+    #[serde(skip)]
+    pub split_of: Option<String>, // cid of the closing half of a one-way order through zero
     #[serde(default = "default_amount", skip)]
     pub front_amount: f64,
     #[serde(default = "default_depth", skip)]
     pub prev_depth: Depth,
+    #[serde(skip)]
+    pub taken_levels: Vec<(usize, f64)>, // (level index, amount) taken from the opposite side in the last execute
     pub avg_price: f64,
     pub side: OrderSide,
     pub state: OrderState,
@@ -360,7 +869,7 @@ pub struct Order {
     #[serde(skip_serializing)]
     pub timestamp: i64,
 }
-fn default_leverage() -> u32 {
+pub(crate) fn default_leverage() -> u32 {
     1
 }
 fn default_amount() -> f64 {
@@ -371,53 +880,125 @@ fn default_depth() -> Depth {
 }
 
 impl Order {
-    pub fn execute(&mut self, depth: &Depth, fill_model: FillModel) -> (f64, f64) {
+    // open long / close short / buy spot take the asks, the others take the bids
+    pub fn is_buy_direction(&self) -> bool {
+        match self.contract_type {
+            ContractType::Spot => self.side == OrderSide::Buy,
+            ContractType::Futures | ContractType::Inverse | ContractType::Options => {
+                (self.side == OrderSide::Buy) == (self.position_side == PositionSide::Long)
+            }
+        }
+    }
+
+    pub fn contract_size(&self) -> f64 {
+        if self.multiplier > 0.0 {
+            self.multiplier
+        } else {
+            1.0
+        }
+    }
+
+    // balance freezed by a buy (open) of amount at price, in the quote or in coin for inverse contracts
+    pub fn margin_needed(&self, price: f64, amount: f64) -> f64 {
+        match self.contract_type {
+            ContractType::Spot => price * amount,
+            ContractType::Futures => round6(amount / self.leverage as f64 * price),
+            ContractType::Inverse if price > 0.0 => {
+                (amount * self.contract_size() / price / self.leverage as f64 * 1e12).round() / 1e12
+            }
+            ContractType::Inverse => 0.0,
+            // premium of longs, margin of shorts
+            ContractType::Options => match self.position_side {
+                PositionSide::Long => (price * amount * self.contract_size() * 1e12).round() / 1e12,
+                PositionSide::Short => (self.option_margin * amount * 1e12).round() / 1e12,
+            },
+        }
+    }
+
+    // stop orders trigger on mid price, same as the stop loss check of positions
+    pub fn is_stop_triggered(&self, depth: &Depth) -> bool {
+        let Some(stop_price) = self.stop_price else {
+            return false;
+        };
+        if depth.asks.is_empty() || depth.bids.is_empty() {
+            return false;
+        }
+        let mid_price = (depth.asks[0].0 + depth.bids[0].0) / 2.0;
+        if self.is_buy_direction() {
+            mid_price >= stop_price
+        } else {
+            mid_price <= stop_price
+        }
+    }
+
+    // amount left to fill in the visible part, the whole rest for non iceberg orders
+    pub fn rest_visible(&self) -> f64 {
+        let rest = self.amount - self.filled_amount;
+        match self.display_amount {
+            Some(display_amount) => rest.min(display_amount - self.display_filled),
+            None => rest,
+        }
+    }
+
+    pub fn execute(&mut self, depth: &Depth, queue_model: impl QueueModel, rng: &mut dyn RngCore) -> (f64, f64) {
+        self.execute_with_trades(depth, &[], queue_model, rng)
+    }
+
+    // trades since the previous depth are handed to the queue model
+    pub fn execute_with_trades(
+        &mut self,
+        depth: &Depth,
+        trades: &[Trade],
+        queue_model: impl QueueModel,
+        rng: &mut dyn RngCore,
+    ) -> (f64, f64) {
+        self.taken_levels.clear();
+        if depth.asks.is_empty() || depth.bids.is_empty() {
+            return (0.0, 0.0);
+        }
         let mut avg_price = 0.0;
         let mut executed_amount = 0.0;
         let mut executed_value = 0.0;
         let mut rest_amount = self.amount - self.filled_amount;
         let mut pos_coefficient = 1.0;
-        if (self.side == OrderSide::Buy && self.position_side == PositionSide::Long && self.contract_type == ContractType::Futures)
-            || (self.side == OrderSide::Sell && self.position_side == PositionSide::Short && self.contract_type == ContractType::Futures)
-                || (self.side == OrderSide::Buy && self.contract_type == ContractType::Spot){
+        if self.is_buy_direction() {
                 // Maintain an execution position queue
                 if depth.asks[0].0 > self.price && rest_amount > 0.0 {
-                    let exe_amount = self.update_front_amount(depth, fill_model);
+                    let exe_amount = self.update_front_amount(depth, trades, &queue_model, rng);
                     if exe_amount != 0.0 {
                         executed_amount += exe_amount;
                         executed_value += exe_amount * self.price;
                         avg_price = executed_value / executed_amount;
                     }
                 } else {
-                    for ask in depth.asks.iter() {
+                    for (index, ask) in depth.asks.iter().enumerate() {
                         if ask.0 > self.price || rest_amount <= 0.0 {
                             break;
                         }
                         self.front_amount = 0.0;
                         let amount_to_execute = rest_amount.min(ask.1);
+                        self.taken_levels.push((index, amount_to_execute));
                         executed_amount += amount_to_execute;
                         executed_value += amount_to_execute * ask.0;
                         avg_price = executed_value / executed_amount;
                         rest_amount -= amount_to_execute;
                     }
                 }
-        }else if (self.side == OrderSide::Buy && self.position_side == PositionSide::Short && self.contract_type == ContractType::Futures)
-        || (self.side == OrderSide::Sell && self.position_side == PositionSide::Long && self.contract_type == ContractType::Futures)
-        || (self.side == OrderSide::Sell && self.contract_type == ContractType::Spot)
-        {
+        } else {
             if depth.bids[0].0 < self.price && rest_amount > 0.0 {
-                let exe_amount = self.update_front_amount(depth, fill_model);
+                let exe_amount = self.update_front_amount(depth, trades, &queue_model, rng);
                 if exe_amount != 0.0 {
                     executed_amount += exe_amount;
                     executed_value += exe_amount * self.price;
                     avg_price = executed_value / executed_amount;
                 }
             } else {
-                for bid in depth.bids.iter() {
+                for (index, bid) in depth.bids.iter().enumerate() {
                     if bid.0 < self.price || rest_amount <= 0.0 {
                         break;
                     }
                     let amount_to_execute = rest_amount.min(bid.1);
+                    self.taken_levels.push((index, amount_to_execute));
                     executed_amount += amount_to_execute;
                     executed_value += amount_to_execute * bid.0;
                     avg_price = executed_value / executed_amount;
@@ -429,118 +1010,79 @@ impl Order {
                 
 
         // this is out of most max precision of post, so it won't change the result
-        executed_amount = (executed_amount * 1e6).round() / 1e6;
         avg_price = (avg_price * 1e12).round() / 1e12;
+        executed_amount = self.record_fill(avg_price, executed_amount, depth.local_timestamp);
+        (avg_price, executed_amount * pos_coefficient)
+    }
+
+    // update filled amount, average price and state, return the rounded executed amount
+    fn record_fill(&mut self, avg_price: f64, executed_amount: f64, timestamp: i64) -> f64 {
+        let executed_amount = (executed_amount * 1e6).round() / 1e6;
         if avg_price > 0.0 {
             self.avg_price = (avg_price * executed_amount + self.avg_price * self.filled_amount)
                 / (self.filled_amount + executed_amount);
         }
         self.filled_amount += executed_amount;
+        if let Some(display_amount) = self.display_amount {
+            self.display_filled = (self.display_filled + executed_amount).min(display_amount);
+            // visible part used up, refill at the back of the queue
+            if self.display_filled >= display_amount && self.filled_amount < self.amount {
+                self.display_filled = 0.0;
+                self.front_amount = -1.0;
+            }
+        }
 
         if self.filled_amount == self.amount {
-            self.timestamp = depth.local_timestamp;
+            self.timestamp = timestamp;
             self.state = OrderState::Filled;
         } else if self.filled_amount > 0.0 {
             self.state = OrderState::PartiallyFilled;
         }
-        (avg_price, executed_amount * pos_coefficient)
+        executed_amount
     }
 
     // ------------- return amount to be filled -------------------
-    #[allow(clippy::needless_return)]
-    fn update_front_amount(&mut self, new_depth: &Depth, fill_model: FillModel) -> f64 {
-        // init amount
-        if self.front_amount < 0.0 {
-            self.front_amount = 0.0;
-            match self.side {
-                OrderSide::Buy => {
-                    for ask in new_depth.asks.iter() {
-                        if ask.0 <= self.price {
-                            self.front_amount = ask.1;
-                            continue;
-                        }
-                        break;
-                    }
-                }
-                OrderSide::Sell => {
-                    for bid in new_depth.bids.iter() {
-                        if bid.0 >= self.price {
-                            self.front_amount = bid.1;
-                            continue;
-                        }
-                        break;
-                    }
+    fn update_front_amount(
+        &mut self,
+        new_depth: &Depth,
+        trades: &[Trade],
+        queue_model: impl QueueModel,
+        rng: &mut dyn RngCore,
+    ) -> f64 {
+        let buy = self.is_buy_direction();
+        // trades at or through the price of the order, the taker sells into resting buys and buys into resting sells
+        let trades: Vec<&Trade> = trades
+            .iter()
+            .filter(|x| {
+                if buy {
+                    x.price <= self.price && !x.side.eq_ignore_ascii_case("buy")
+                } else {
+                    x.price >= self.price && !x.side.eq_ignore_ascii_case("sell")
                 }
-            }
+            })
+            .collect();
+        // init amount, the level of the order on its own side of the book
+        if self.front_amount < 0.0 {
+            self.front_amount = new_depth.level_amount(buy, self.price);
             self.prev_depth = new_depth.clone();
-            return 0.0;
-        }
-        let mut prev_amount = 0.0;
-        let mut new_amount = 0.0;
-        match self.side {
-            OrderSide::Buy => {
-                for ask in new_depth.asks.iter() {
-                    if ask.0 <= self.price {
-                        new_amount = ask.1;
-                        continue;
-                    }
-                    break;
-                }
-                for ask in self.prev_depth.asks.iter() {
-                    if ask.0 <= self.price {
-                        prev_amount = ask.1;
-                        continue;
-                    }
-                    break;
-                }
+            if trades.is_empty() {
+                return 0.0;
             }
-            OrderSide::Sell => {
-                for bid in new_depth.bids.iter() {
-                    if bid.0 >= self.price {
-                        new_amount = bid.1;
-                        continue;
-                    }
-                    break;
-                }
-                for bid in self.prev_depth.bids.iter() {
-                    if bid.0 >= self.price {
-                        prev_amount = bid.1;
-                        continue;
-                    }
-                    break;
-                }
-            }
-        };
-        let chg = prev_amount - new_amount;
-        if chg < 0.0 {
-            self.front_amount = self.front_amount.min(new_amount);
-            return 0.0;
-        }
-
-        let front = self.front_amount;
-        let back = prev_amount - front;
-
-        let mut prob = fill_model.prob(back, front);
-        if prob > 1.0 || prob.is_infinite() {
-            prob = 1.0;
-        }
-        let new_front = front - (1.0 - prob) * chg + (back - prob * chg).min(0.0);
-        self.front_amount = new_front.min(new_amount).min(0.0);
-        // match success on front amount update
-        if self.front_amount == 0.0
-            && ((self.side == OrderSide::Buy && (self.price - new_depth.bids[0].0).abs() < 1e-11)
-                || (self.side == OrderSide::Sell
-                    && (new_depth.asks[0].0 - self.price).abs() < 1e-11))
-        {
-            let mut ret = chg;
-
-            if chg > self.amount - self.filled_amount {
-                ret = self.amount - self.filled_amount;
-            }
-            ret = (ret * 1e6).round() / 1e6;
-            return ret;
         }
-        return 0.0;
+        let update = queue_model.update(&QueueLevel {
+            price: self.price,
+            buy,
+            front_amount: self.front_amount,
+            rest_amount: self.rest_visible(),
+            prev_amount: self.prev_depth.level_amount(buy, self.price),
+            new_amount: new_depth.level_amount(buy, self.price),
+            prev_depth: &self.prev_depth,
+            new_depth,
+            trades: &trades,
+        }, rng);
+        self.prev_depth = new_depth.clone();
+        self.front_amount = update.front_amount;
+        update.filled_amount.clamp(0.0, self.rest_visible())
     }
 }
 
@@ -561,31 +1103,51 @@ pub struct Depth {
     pub local_timestamp: i64,
 }
 
+impl Depth {
+    // amount at exactly this price on the bids or the asks, 0 if there is no such level
+    pub fn level_amount(&self, bids: bool, price: f64) -> f64 {
+        let levels = if bids { &self.bids } else { &self.asks };
+        levels
+            .iter()
+            .find(|level| (level.0 - price).abs() < 1e-11)
+            .map_or(0.0, |level| level.1)
+    }
+}
+
+impl Exchange {
+    // exchange names used in the database
+    pub fn from_name(name: &str) -> Option<Exchange> {
+        match name {
+            "binance" => Some(Exchange::BinanceSpot),
+            "coinbase" => Some(Exchange::CoinbaseSpot),
+            "kraken" => Some(Exchange::KrakenSpot),
+            "okx" => Some(Exchange::OkxSpot),
+            "okex" => Some(Exchange::OkxSpot),
+            "okx_futures" => Some(Exchange::OkxSwap),
+            "binance-futures" => Some(Exchange::BinanceSwap),
+            "binance_futures" => Some(Exchange::BinanceSwap),
+            "BinanceSpot" => Some(Exchange::BinanceSpot),
+            "CoinbaseSpot" => Some(Exchange::CoinbaseSpot),
+            "KrakenSpot" => Some(Exchange::KrakenSpot),
+            "OkxSpot" => Some(Exchange::OkxSpot),
+            "OkexSpot" => Some(Exchange::OkxSpot),
+            "bybit" => Some(Exchange::BybitSwap),
+            "bitget_futures" => Some(Exchange::BitgetSwap),
+            "bybit_swap" => Some(Exchange::BybitSwap),
+            "bitget_swap" => Some(Exchange::BitgetSwap),
+            // 更多匹配
+            _ => None,
+        }
+    }
+}
+
 fn deserialize_exchange<'de, D>(deserializer: D) -> Result<Exchange, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    match s.as_str() {
-        "binance" => Ok(Exchange::BinanceSpot),
-        "coinbase" => Ok(Exchange::CoinbaseSpot),
-        "kraken" => Ok(Exchange::KrakenSpot),
-        "okx" => Ok(Exchange::OkxSpot),
-        "okex" => Ok(Exchange::OkxSpot),
-        "okx_futures" => Ok(Exchange::OkxSwap),
-        "binance-futures" => Ok(Exchange::BinanceSwap),
-        "BinanceSpot" => Ok(Exchange::BinanceSpot),
-        "CoinbaseSpot" => Ok(Exchange::CoinbaseSpot),
-        "KrakenSpot" => Ok(Exchange::KrakenSpot),
-        "OkxSpot" => Ok(Exchange::OkxSpot),
-        "OkexSpot" => Ok(Exchange::OkxSpot),
-        "bybit" => Ok(Exchange::BybitSwap),
-        "bitget_futures" => Ok(Exchange::BitgetSwap),
-        "bybit_swap" => Ok(Exchange::BybitSwap),
-        "bitget_swap" => Ok(Exchange::BitgetSwap),
-        // 更多匹配
-        _ => Err(serde::de::Error::custom(format!("Unknown exchange: {}", s))),
-    }
+    Exchange::from_name(&s)
+        .ok_or_else(|| serde::de::Error::custom(format!("Unknown exchange: {}", s)))
 }
 
 #[derive(Default, Debug, Deserialize, Serialize, Clone, Row)]
@@ -606,6 +1168,7 @@ mod tests {
 
     use super::*;
     use crate::engine::FillModel;
+    use crate::queue::TradeQueueModel;
 
     #[test]
     fn test_order_execute() {
@@ -646,13 +1209,128 @@ mod tests {
             local_timestamp: 0,
         };
         let fill_model = FillModel::None;
-        let (avg_price, executed_amount) = order.execute(&depth, fill_model);
+        let (avg_price, executed_amount) = order.execute(&depth, fill_model, &mut rand::thread_rng());
         assert_eq!(avg_price, 100.0);
         assert_eq!(executed_amount, 10.0);
         assert_eq!(order.filled_amount, 10.0);
         assert_eq!(order.state, OrderState::Filled);
     }
 
+    #[test]
+    fn test_order_queue_own_side() {
+        let mut order = Order {
+            side: OrderSide::Buy,
+            price: 99.0,
+            amount: 1.0,
+            front_amount: -1.0,
+            ..Default::default()
+        };
+        let mut depth = Depth {
+            bids: vec![(99.0, 5.0)],
+            asks: vec![(101.0, 5.0)],
+            ..Default::default()
+        };
+        // a resting buy queues behind the bids at its price
+        assert_eq!(order.execute(&depth, FillModel::None, &mut rand::thread_rng()), (0.0, 0.0));
+        assert_eq!(order.front_amount, 5.0);
+        depth.bids = vec![(99.0, 2.0)];
+        assert_eq!(order.execute(&depth, FillModel::None, &mut rand::thread_rng()), (0.0, 0.0));
+        assert_eq!(order.front_amount, 2.0);
+    }
+
+    #[test]
+    fn test_stop_order_trigger() {
+        let mut order = Order {
+            contract_type: ContractType::Futures,
+            position_side: PositionSide::Long,
+            side: OrderSide::Sell,
+            order_type: OrderType::Market,
+            price: 95.0,
+            amount: 1.0,
+            stop_price: Some(95.0),
+            ..Default::default()
+        };
+        let mut depth = Depth {
+            bids: vec![(99.0, 1.0)],
+            asks: vec![(101.0, 1.0)],
+            ..Default::default()
+        };
+        assert!(!order.is_stop_triggered(&depth));
+        depth.bids[0].0 = 94.0;
+        depth.asks[0].0 = 95.0;
+        assert!(order.is_stop_triggered(&depth));
+        // closing a short buys, it triggers on the way up
+        order.position_side = PositionSide::Short;
+        order.stop_price = Some(100.0);
+        assert!(!order.is_stop_triggered(&depth));
+        depth.bids[0].0 = 100.0;
+        depth.asks[0].0 = 100.5;
+        assert!(order.is_stop_triggered(&depth));
+    }
+
+    #[test]
+    fn test_iceberg_refill() {
+        let mut order = Order {
+            side: OrderSide::Buy,
+            price: 100.0,
+            amount: 3.0,
+            display_amount: Some(1.0),
+            front_amount: -1.0,
+            ..Default::default()
+        };
+        let depth = Depth {
+            bids: vec![(99.0, 5.0)],
+            asks: vec![(100.0, 2.0)],
+            ..Default::default()
+        };
+        assert_eq!(order.rest_visible(), 1.0);
+        // crossing the book takes the whole rest, not only the visible part
+        let (_, executed_amount) = order.execute(&depth, FillModel::None, &mut rand::thread_rng());
+        assert_eq!(executed_amount, 2.0);
+        assert_eq!(order.state, OrderState::PartiallyFilled);
+        // refilled at the back of the queue
+        assert_eq!(order.rest_visible(), 1.0);
+        assert_eq!(order.front_amount, -1.0);
+    }
+
+    #[test]
+    fn test_order_execute_trade() {
+        let mut order = Order {
+            side: OrderSide::Sell,
+            price: 100.0,
+            amount: 2.0,
+            front_amount: -1.0,
+            ..Default::default()
+        };
+        let depth = Depth {
+            bids: vec![(99.0, 5.0)],
+            asks: vec![(100.0, 3.0)],
+            ..Default::default()
+        };
+        let mut trade = Trade {
+            price: 100.0,
+            amount: 2.0,
+            side: "buy".to_string(),
+            ..Default::default()
+        };
+        let execute = |order: &mut Order, trade: &Trade| {
+            order.execute_with_trades(&depth, std::slice::from_ref(trade), TradeQueueModel, &mut rand::thread_rng())
+        };
+        // 3 queued ahead of us
+        assert_eq!(execute(&mut order, &trade), (0.0, 0.0));
+        assert_eq!(order.front_amount, 1.0);
+        assert_eq!(execute(&mut order, &trade), (100.0, -1.0));
+        assert_eq!(order.state, OrderState::PartiallyFilled);
+        // a taker sell does not fill a resting sell
+        trade.side = "sell".to_string();
+        assert_eq!(execute(&mut order, &trade), (0.0, 0.0));
+        // through our price
+        trade.side = "buy".to_string();
+        trade.price = 100.5;
+        assert_eq!(execute(&mut order, &trade), (100.0, -1.0));
+        assert_eq!(order.state, OrderState::Filled);
+    }
+
     #[test]
     fn test_position_update_pos() {
         let mut position = Position {
@@ -664,7 +1342,7 @@ mod tests {
             amount_available: dec!(0),
             amount_freezed: dec!(0),
             entry_price: dec!(0),
-            stop_loss: vec![(dec!(0), dec!(0))],
+            ..Default::default()
         };
         let filled = FilledStack {
             exchange: Exchange::BinanceSwap,
@@ -685,6 +1363,188 @@ mod tests {
         assert_eq!(position.margin_value, dec!(100));
     }
 
+    #[test]
+    fn test_inverse_fill() {
+        let mut account = Account::default();
+        account.wallet.insert(
+            "BTC".to_string(),
+            Balance { total: dec!(1), available: dec!(1), freezed: dec!(0) },
+        );
+        let order = Order {
+            contract_type: ContractType::Inverse,
+            symbol: "BTC_USD".to_string(),
+            side: OrderSide::Buy,
+            price: 50000.0,
+            amount: 100.0,
+            leverage: 10,
+            multiplier: 100.0,
+            ..Default::default()
+        };
+        // 100 contracts of 100 usd at 10x
+        let margin = order.margin_needed(order.price, order.amount);
+        assert_eq!(margin, 0.02);
+        account
+            .margin_balance(&order.contract_type, &order.symbol)
+            .add_freezed(Decimal::from_f64(margin).unwrap());
+        let open = FilledStack::from_order(&order, 50000.0, 100.0);
+        account.margin_balance(&open.contract_type, &open.symbol).fill_freezed(&open);
+        let mut position = Position::default();
+        position.update_pos(&open);
+        assert_eq!(position.entry_price, dec!(50000));
+        assert_eq!(position.margin_value, dec!(0.02));
+
+        let close = FilledStack {
+            open_price: Some(dec!(50000)),
+            ..FilledStack::from_order(&Order { side: OrderSide::Sell, ..order.clone() }, 55000.0, -100.0)
+        };
+        account.margin_balance(&close.contract_type, &close.symbol).fill_freezed(&close);
+        position.update_pos(&close);
+        assert_eq!(position.amount_total, dec!(0));
+        // pnl in coin: 10000 / 50000 - 10000 / 55000
+        let wallet = &account.wallet["BTC"];
+        assert_eq!(wallet.total, dec!(1.018181818182));
+        assert_eq!(wallet.freezed, dec!(0));
+        assert_eq!(account.balance.total, dec!(0));
+        assert_eq!(split_symbol("BTCUSD"), ("BTC".to_string(), "USD".to_string()));
+    }
+
+    #[test]
+    fn test_inverse_equity() {
+        let mut account = Account { currency: "USD".to_string(), ..Default::default() };
+        account.wallet.insert(
+            "BTC".to_string(),
+            Balance { total: dec!(1), available: dec!(1), freezed: dec!(0) },
+        );
+        let marks = HashMap::from([((Exchange::BinanceSwap, "BTCUSD".to_string()), 50000.0)]);
+        account.mark_to_market(&marks);
+        assert_eq!(account.equity, dec!(50000));
+
+        // the margin out of the wallet is still the account's
+        let order = Order {
+            contract_type: ContractType::Inverse,
+            exchange: Exchange::BinanceSwap,
+            symbol: "BTC_USD".to_string(),
+            side: OrderSide::Buy,
+            price: 50000.0,
+            amount: 100.0,
+            leverage: 10,
+            multiplier: 100.0,
+            ..Default::default()
+        };
+        let margin = Decimal::from_f64(order.margin_needed(order.price, order.amount)).unwrap();
+        account.margin_balance(&order.contract_type, &order.symbol).add_freezed(margin);
+        let open = FilledStack::from_order(&order, 50000.0, 100.0);
+        account.margin_balance(&open.contract_type, &open.symbol).fill_freezed(&open);
+        let key = ("BTC_USD".to_string(), PositionSide::Long, Exchange::BinanceSwap);
+        account.position.entry(key).or_default().update_pos(&open);
+        account.mark_to_market(&marks);
+        assert_eq!(account.wallet["BTC"].total, dec!(0.98));
+        assert_eq!(account.equity, dec!(50000));
+
+        // 10000 usd long, 10000 / 50000 - 10000 / 55000 btc at 55000
+        let marks = HashMap::from([((Exchange::BinanceSwap, "BTCUSD".to_string()), 55000.0)]);
+        account.mark_to_market(&marks);
+        assert_eq!(account.equity.round_dp(2), dec!(56000));
+    }
+
+    #[test]
+    fn test_multi_asset_spot() {
+        let mut account = Account {
+            balance: Balance { total: dec!(1000), available: dec!(1000), freezed: dec!(0) },
+            currency: "USDT".to_string(),
+            ..Default::default()
+        };
+        account.wallet.insert(
+            "ETH".to_string(),
+            Balance { total: dec!(1), available: dec!(1), freezed: dec!(0) },
+        );
+        let post = |account: &mut Account, symbol: &str, side: OrderSide, price: f64, amount: f64| {
+            let order = Order {
+                symbol: symbol.to_string(),
+                side: side.clone(),
+                price,
+                amount,
+                ..Default::default()
+            };
+            let dec_amount = Decimal::from_f64(amount).unwrap();
+            match side {
+                OrderSide::Buy => account
+                    .margin_balance(&order.contract_type, symbol)
+                    .add_freezed(Decimal::from_f64(price).unwrap() * dec_amount),
+                OrderSide::Sell => account.holding(symbol).add_freezed(dec_amount),
+            }
+            let filled = if side == OrderSide::Buy { amount } else { -amount };
+            account.fill_freezed(&FilledStack::from_order(&order, price, filled));
+        };
+        // usdt -> btc, then btc -> eth, then the starting eth back to btc
+        post(&mut account, "BTC_USDT", OrderSide::Buy, 50000.0, 0.01);
+        post(&mut account, "ETH_BTC", OrderSide::Buy, 0.05, 0.1);
+        post(&mut account, "ETHBTC", OrderSide::Sell, 0.06, 1.0);
+        assert_eq!(account.balance.total, dec!(500));
+        assert_eq!(account.wallet["BTC"].total, dec!(0.065));
+        assert_eq!(account.wallet["BTC"].available, dec!(0.065));
+        assert_eq!(account.wallet["ETH"].total, dec!(0.1));
+        assert_eq!(account.wallet["ETH"].freezed, dec!(0));
+        assert!(account.position.is_empty());
+        // every holding with a symbol quoted in usdt is valued
+        let marks = HashMap::from([
+            ((Exchange::BinanceSpot, "BTCUSDT".to_string()), 50000.0),
+            ((Exchange::BinanceSpot, "ETHUSDT".to_string()), 2500.0),
+            ((Exchange::BinanceSpot, "ETHBTC".to_string()), 0.05),
+        ]);
+        account.mark_to_market(&marks);
+        assert_eq!(account.equity, dec!(4000));
+    }
+
+    #[test]
+    fn test_mark_by_symbol() {
+        let mut account = Account::default();
+        for (symbol, amount, entry_price) in [("BTC_USDT", dec!(1), dec!(100)), ("ETH-USDT", dec!(2), dec!(10))] {
+            let position = Position {
+                side: PositionSide::Long,
+                exchange: Exchange::BinanceSwap,
+                amount_total: amount,
+                entry_price,
+                contract_type: ContractType::Futures,
+                ..Default::default()
+            };
+            account.position.insert((symbol.to_string(), PositionSide::Long, Exchange::BinanceSwap), position);
+        }
+        let marks = HashMap::from([
+            ((Exchange::BinanceSwap, "BTCUSDT".to_string()), 110.0),
+            ((Exchange::BinanceSwap, "ETHUSDT".to_string()), 9.0),
+        ]);
+        account.mark_to_market(&marks);
+        // +10 on btc, -2 on eth
+        assert_eq!(account.unrealized_pnl, dec!(8));
+    }
+
+    #[test]
+    fn test_futures_close_pnl_sign() {
+        // open at 100 with 2x, close at 110: longs gain 10, shorts lose 10
+        let settle = |side: PositionSide, sign: Decimal| {
+            let mut balance = Balance { total: dec!(1000), available: dec!(1000), freezed: dec!(0) };
+            let fill = |price: Decimal, amount: Decimal| FilledStack {
+                side: side.clone(),
+                contract_type: ContractType::Futures,
+                leverage: 2,
+                filled_price: price,
+                filled_amount: amount,
+                post_price: price,
+                open_price: Some(dec!(100)),
+                ..Default::default()
+            };
+            balance.add_freezed(dec!(50));
+            balance.fill_freezed_futures(&fill(dec!(100), sign));
+            balance.fill_freezed_futures(&fill(dec!(110), -sign));
+            balance
+        };
+        let long = settle(PositionSide::Long, dec!(1));
+        assert_eq!((long.total, long.available, long.freezed), (dec!(1010), dec!(1010), dec!(0)));
+        let short = settle(PositionSide::Short, dec!(-1));
+        assert_eq!((short.total, short.available, short.freezed), (dec!(990), dec!(990), dec!(0)));
+    }
+
     #[test]
     fn test_balance_update(){
         let balance = Balance{
@@ -696,6 +1556,9 @@ mod tests {
             backtest_id: "test".to_string(),
             balance,
             position: HashMap::new(),
+            wallet: HashMap::new(),
+            currency: "USDT".to_string(),
+            ..Default::default()
         };
         // let account = self
         //     .account
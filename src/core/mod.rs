@@ -1,5 +1,9 @@
+pub mod algo;
 pub mod dataloader;
 pub mod engine;
+pub mod impact;
+pub mod instrument;
 pub mod market;
+pub mod queue;
 pub mod recorder;
 pub mod server;
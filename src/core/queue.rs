@@ -0,0 +1,186 @@
+use crate::engine::FillModel;
+use crate::market::*;
+use crate::round::round6;
+use rand::RngCore;
+use sonic_rs::{Deserialize, Serialize};
+
+// queue model of a backtest
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum QueueModelType {
+    #[default]
+    Depth, // level decreases split by the fill model
+    Trade, // only prints deplete the queue, needs the trade data
+}
+
+impl QueueModelType {
+    pub fn build(&self, fill_model: &FillModel) -> Box<dyn QueueModel> {
+        match self {
+            QueueModelType::Depth => Box::new(fill_model.clone()),
+            QueueModelType::Trade => Box::new(TradeQueueModel),
+        }
+    }
+}
+
+// what a queue model sees of the order's price level between two depths
+pub struct QueueLevel<'a> {
+    pub price: f64,
+    pub buy: bool, // resting on the bids
+    pub front_amount: f64, // amount queued ahead of the order
+    pub rest_amount: f64, // amount of the order left to fill
+    pub prev_amount: f64, // level amount in prev_depth
+    pub new_amount: f64, // level amount in new_depth
+    pub prev_depth: &'a Depth,
+    pub new_depth: &'a Depth,
+    pub trades: &'a [&'a Trade], // trades at or through the price since prev_depth
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueueUpdate {
+    pub front_amount: f64,
+    pub filled_amount: f64,
+}
+
+/// Estimates how the queue ahead of a resting order depletes, and how much of the order is filled.
+/// Random draws take the backtest's seeded `rng`, so a run can be reproduced.
+pub trait QueueModel: Send + Sync {
+    fn update(&self, level: &QueueLevel, rng: &mut dyn RngCore) -> QueueUpdate;
+}
+
+impl<T: QueueModel + ?Sized> QueueModel for &T {
+    fn update(&self, level: &QueueLevel, rng: &mut dyn RngCore) -> QueueUpdate {
+        (**self).update(level, rng)
+    }
+}
+
+impl QueueLevel<'_> {
+    // the order is at the best price of its side
+    pub fn at_best(&self) -> bool {
+        let best = if self.buy {
+            self.new_depth.bids.first()
+        } else {
+            self.new_depth.asks.first()
+        };
+        best.is_some_and(|level| (level.0 - self.price).abs() < 1e-11)
+    }
+}
+
+// level decrease is split between front and back of the queue by the fill probability
+impl QueueModel for FillModel {
+    fn update(&self, level: &QueueLevel, rng: &mut dyn RngCore) -> QueueUpdate {
+        let chg = level.prev_amount - level.new_amount;
+        if chg < 0.0 {
+            return QueueUpdate {
+                front_amount: level.front_amount.min(level.new_amount),
+                filled_amount: 0.0,
+            };
+        }
+
+        let front = level.front_amount;
+        let back = level.prev_amount - front;
+
+        let mut prob = self.prob(back, front, rng);
+        if prob > 1.0 || prob.is_infinite() {
+            prob = 1.0;
+        }
+        let new_front = front - (1.0 - prob) * chg + (back - prob * chg).min(0.0);
+        let front_amount = new_front.min(level.new_amount).max(0.0);
+        // match success on front amount update
+        let filled_amount = if front_amount == 0.0 && level.at_best() {
+            round6(chg.min(level.rest_amount))
+        } else {
+            0.0
+        };
+        QueueUpdate {
+            front_amount,
+            filled_amount,
+        }
+    }
+}
+
+/// Only trades deplete the queue, a smaller level means cancels ahead of the order.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TradeQueueModel;
+
+impl QueueModel for TradeQueueModel {
+    fn update(&self, level: &QueueLevel, _rng: &mut dyn RngCore) -> QueueUpdate {
+        let mut front = level.front_amount.min(level.new_amount);
+        let mut filled_amount = 0.0;
+        for trade in level.trades {
+            if (trade.price - level.price).abs() < 1e-11 {
+                // at our price, the queue ahead goes first
+                let rest = trade.amount - front;
+                front = (front - trade.amount).max(0.0);
+                if rest > 0.0 {
+                    filled_amount += rest;
+                }
+            } else {
+                // through our price, the whole level is gone
+                front = 0.0;
+                filled_amount += trade.amount;
+            }
+        }
+        QueueUpdate {
+            front_amount: front,
+            filled_amount: round6(filled_amount.min(level.rest_amount)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_model_queue_depleted() {
+        let depth = Depth {
+            bids: vec![(100.0, 1.0)],
+            asks: vec![(101.0, 5.0)],
+            ..Default::default()
+        };
+        let level = QueueLevel {
+            price: 100.0,
+            buy: true,
+            front_amount: 1.0,
+            rest_amount: 1.0,
+            prev_amount: 3.0,
+            new_amount: 1.0,
+            prev_depth: &depth,
+            new_depth: &depth,
+            trades: &[],
+        };
+        // the decrease of 2 goes to the front, the 1 ahead of us is gone and we fill
+        let update = FillModel::Random(0.0, 1e-12).update(&level, &mut rand::thread_rng());
+        assert_eq!(update.front_amount, 0.0);
+        assert_eq!(update.filled_amount, 1.0);
+    }
+
+    #[test]
+    fn test_trade_queue_model() {
+        let depth = Depth {
+            bids: vec![(100.0, 5.0)],
+            asks: vec![(101.0, 5.0)],
+            ..Default::default()
+        };
+        let trade = Trade {
+            price: 100.0,
+            amount: 4.0,
+            ..Default::default()
+        };
+        let trades = [&trade, &trade];
+        let level = QueueLevel {
+            price: 100.0,
+            buy: true,
+            front_amount: 5.0,
+            rest_amount: 2.0,
+            prev_amount: 5.0,
+            new_amount: 5.0,
+            prev_depth: &depth,
+            new_depth: &depth,
+            trades: &trades,
+        };
+        // 5 ahead of us, 8 traded, 3 left for us but only 2 to fill
+        let update = TradeQueueModel.update(&level, &mut rand::thread_rng());
+        assert_eq!(update.front_amount, 0.0);
+        assert_eq!(update.filled_amount, 2.0);
+    }
+}
@@ -42,6 +42,9 @@ impl ZileanServer {
 pub struct BacktestResponse {
     pub status: BacktestStatus,
     pub message: String,
+    // launch response only, the simulation assumptions of the backtest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<BtConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -56,6 +59,7 @@ impl BacktestResponse {
         Self {
             status: BacktestStatus::Error,
             message,
+            config: None,
         }
     }
 
@@ -63,6 +67,15 @@ impl BacktestResponse {
         Self {
             status: BacktestStatus::Ok,
             message,
+            config: None,
+        }
+    }
+
+    pub fn launched(backtest_id: String, config: BtConfig) -> Self {
+        Self {
+            status: BacktestStatus::Ok,
+            message: backtest_id,
+            config: Some(config),
         }
     }
 }
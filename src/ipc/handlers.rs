@@ -1,7 +1,8 @@
 use zmq::Socket;
 use crate::{engine::ZileanV1, server::BacktestResponse};
 use super::traits::{TradingEngine, MessageResponder, Command, CommandParser};
-use crate::market::Order;
+use crate::algo::AlgoOrder;
+use crate::market::{AdjustMargin, AmendOrder, BatchOp, CancelAll, ClosePosition, FillQuery, Order, SetLeverage, Transfer};
 use serde::Serialize;
 use log::{info, error};
 
@@ -40,17 +41,71 @@ impl TradingEngine for ZileanV1 {
     }
     
     fn handle_post_order(&mut self, order: Order) -> BacktestResponse {
-        self.post_order(order)
+        self.post_client_order(order)
     }
     
     fn handle_cancel_order(&mut self, cid: String) -> BacktestResponse {
         self.cancel_order(cid)
     }
     
-    fn handle_close_position(&mut self, symbol: String) -> BacktestResponse {
-        // 注意：原始代码中CLOSE_POSITION调用的是cancel_order，这里保持一致
-        // 如果需要不同的逻辑，可以在这里实现
-        self.cancel_order(symbol)
+    fn handle_cancel_all(&mut self, filter: CancelAll) -> BacktestResponse {
+        self.cancel_all(filter)
+    }
+    
+    fn handle_amend_order(&mut self, amend: AmendOrder) -> BacktestResponse {
+        self.amend_order(amend)
+    }
+    
+    fn handle_batch(&mut self, ops: Vec<BatchOp>) -> BacktestResponse {
+        self.batch(ops)
+    }
+    
+    fn handle_step(&mut self, ops: Vec<BatchOp>, tick: &str) -> BacktestResponse {
+        self.step(ops, tick)
+    }
+    
+    fn handle_post_algo(&mut self, algo: AlgoOrder) -> BacktestResponse {
+        self.post_algo(algo)
+    }
+    
+    fn handle_cancel_algo(&mut self, algo_id: String) -> BacktestResponse {
+        self.cancel_algo(algo_id)
+    }
+    
+    fn handle_set_leverage(&mut self, request: SetLeverage) -> BacktestResponse {
+        self.set_leverage(request)
+    }
+    
+    fn handle_adjust_margin(&mut self, request: AdjustMargin, add: bool) -> BacktestResponse {
+        self.adjust_margin(request, add)
+    }
+    
+    fn handle_transfer(&mut self, transfer: Transfer, deposit: bool) -> BacktestResponse {
+        if deposit {
+            self.deposit(transfer)
+        } else {
+            self.withdraw(transfer)
+        }
+    }
+    
+    fn handle_close_position(&mut self, request: ClosePosition) -> BacktestResponse {
+        self.close_position(request)
+    }
+    
+    fn handle_get_order(&mut self, cid: String) -> BacktestResponse {
+        self.get_order(cid)
+    }
+    
+    fn handle_get_open_orders(&mut self) -> BacktestResponse {
+        self.get_open_orders()
+    }
+    
+    fn handle_get_account(&mut self) -> BacktestResponse {
+        self.get_account()
+    }
+    
+    fn handle_get_fills(&mut self, query: FillQuery) -> BacktestResponse {
+        self.get_fills(query)
     }
 }
 
@@ -77,6 +132,7 @@ impl ZileanCommandHandler {
                     Err("No tick data available".to_string())
                 }
                 else {
+                    engine.deliver_tick();
                     // 直接返回成功响应，包含缓存的tick数据
                     Ok(crate::server::BacktestResponse::normal_response(self.next_tick.clone()))
                 }
@@ -87,8 +143,58 @@ impl ZileanCommandHandler {
             Command::CancelOrder(cid) => {
                 Ok(engine.handle_cancel_order(cid))
             },
-            Command::ClosePosition(symbol) => {
-                Ok(engine.handle_close_position(symbol))
+            Command::CancelAll(filter) => {
+                Ok(engine.handle_cancel_all(filter))
+            },
+            Command::AmendOrder(amend) => {
+                Ok(engine.handle_amend_order(amend))
+            },
+            Command::Batch(ops) => {
+                Ok(engine.handle_batch(ops))
+            },
+            Command::Step(ops) => {
+                if self.next_tick.is_empty() {
+                    Err("No tick data available".to_string())
+                }
+                else {
+                    Ok(engine.handle_step(ops, &self.next_tick))
+                }
+            },
+            Command::PostAlgo(algo) => {
+                Ok(engine.handle_post_algo(algo))
+            },
+            Command::CancelAlgo(algo_id) => {
+                Ok(engine.handle_cancel_algo(algo_id))
+            },
+            Command::SetLeverage(request) => {
+                Ok(engine.handle_set_leverage(request))
+            },
+            Command::AddMargin(request) => {
+                Ok(engine.handle_adjust_margin(request, true))
+            },
+            Command::ReduceMargin(request) => {
+                Ok(engine.handle_adjust_margin(request, false))
+            },
+            Command::Deposit(transfer) => {
+                Ok(engine.handle_transfer(transfer, true))
+            },
+            Command::Withdraw(transfer) => {
+                Ok(engine.handle_transfer(transfer, false))
+            },
+            Command::ClosePosition(request) => {
+                Ok(engine.handle_close_position(request))
+            },
+            Command::GetOrder(cid) => {
+                Ok(engine.handle_get_order(cid))
+            },
+            Command::GetOpenOrders => {
+                Ok(engine.handle_get_open_orders())
+            },
+            Command::GetAccount => {
+                Ok(engine.handle_get_account())
+            },
+            Command::GetFills(query) => {
+                Ok(engine.handle_get_fills(query))
             },
             Command::Close => {
                 info!("Server close command received.");
@@ -1,5 +1,5 @@
 use serde::Serialize;
-use crate::{market::Order, server::BacktestResponse};
+use crate::{algo::AlgoOrder, market::{AdjustMargin, AmendOrder, BatchOp, CancelAll, ClosePosition, FillQuery, Order, SetLeverage, Transfer}, server::BacktestResponse};
 
 /// 定义交易引擎的核心操作接口
 /// 这个trait抽象了交易引擎的主要功能，隐藏了内部实现细节
@@ -17,11 +17,75 @@ pub trait TradingEngine {
     /// 参数: cid - 订单客户端ID
     /// 返回: 撤单结果响应
     fn handle_cancel_order(&mut self, cid: String) -> BacktestResponse;
+
+    /// 处理批量撤单请求
+    /// 参数: filter - 交易所、交易对、方向及持仓方向, 为空时匹配所有订单
+    /// 返回: 撤销订单的cid列表
+    fn handle_cancel_all(&mut self, filter: CancelAll) -> BacktestResponse;
+
+    /// 处理改单请求
+    /// 参数: amend - 订单cid及新的价格/数量
+    /// 返回: 改单结果响应
+    fn handle_amend_order(&mut self, amend: AmendOrder) -> BacktestResponse;
+
+    /// 处理批量请求, 按顺序执行下单/撤单/改单, 任一失败则全部回滚
+    /// 参数: ops - 操作列表
+    /// 返回: 每个操作的响应列表
+    fn handle_batch(&mut self, ops: Vec<BatchOp>) -> BacktestResponse;
+
+    /// 处理单步请求, 执行批量操作后推进到下一个tick, 相当于BATCH加TICK
+    /// 参数: ops - 操作列表, tick - 缓存的下一个tick
+    /// 返回: 每个操作的响应列表及新的tick, 操作失败时回滚且不推进
+    fn handle_step(&mut self, ops: Vec<BatchOp>, tick: &str) -> BacktestResponse;
+
+    /// 处理算法母单请求 (TWAP/VWAP)
+    /// 参数: algo - 算法母单
+    /// 返回: 下单结果响应
+    fn handle_post_algo(&mut self, algo: AlgoOrder) -> BacktestResponse;
+
+    /// 处理算法母单撤单请求
+    /// 参数: algo_id - 母单ID
+    /// 返回: 撤单结果响应
+    fn handle_cancel_algo(&mut self, algo_id: String) -> BacktestResponse;
     
-    /// 处理平仓请求
-    /// 参数: symbol - 交易对符号
-    /// 返回: 平仓结果响应
-    fn handle_close_position(&mut self, symbol: String) -> BacktestResponse;
+    /// 处理调整杠杆请求
+    /// 参数: request - 交易所、交易对、持仓方向及新杠杆
+    /// 返回: 调整结果响应
+    fn handle_set_leverage(&mut self, request: SetLeverage) -> BacktestResponse;
+
+    /// 处理追加/减少逐仓保证金请求
+    /// 参数: request - 持仓及保证金数量, add - 追加为true, 减少为false
+    /// 返回: 调整后的保证金及强平价格
+    fn handle_adjust_margin(&mut self, request: AdjustMargin, add: bool) -> BacktestResponse;
+
+    /// 处理充值/提现请求
+    /// 参数: transfer - 币种及数量, deposit - 充值为true, 提现为false
+    /// 返回: 划转结果响应
+    fn handle_transfer(&mut self, transfer: Transfer, deposit: bool) -> BacktestResponse;
+
+    /// 处理平仓请求, 撤销该持仓的挂单并以市价或激进限价单平仓
+    /// 参数: request - 交易所、交易对及持仓方向
+    /// 返回: 平仓订单cid及成交
+    fn handle_close_position(&mut self, request: ClosePosition) -> BacktestResponse;
+
+    /// 查询订单, 已成交或已撤销的订单在列表中保留数秒
+    /// 最近送达的tick中的订单状态, 之后提交的订单立即可见, 成交与撤单随下一个tick可见
+    /// 参数: cid - 订单客户端ID
+    /// 返回: 订单状态
+    fn handle_get_order(&mut self, cid: String) -> BacktestResponse;
+
+    /// 查询所有未完成订单
+    /// 返回: 订单列表
+    fn handle_get_open_orders(&mut self) -> BacktestResponse;
+
+    /// 查询账户状态, 即最近送达的tick中经过ack_latency后的账户
+    /// 返回: 账户余额、持仓及盈亏
+    fn handle_get_account(&mut self) -> BacktestResponse;
+
+    /// 查询成交记录, 仅包含最近送达的tick中已结算的成交
+    /// 参数: query - 订单cid及起始时间, 为空时返回所有成交
+    /// 返回: 成交列表
+    fn handle_get_fills(&mut self, query: FillQuery) -> BacktestResponse;
     
 }
 
@@ -53,7 +117,22 @@ pub enum Command {
     Tick,
     PostOrder(Order),
     CancelOrder(String),
-    ClosePosition(String),
+    CancelAll(CancelAll),
+    AmendOrder(AmendOrder),
+    Batch(Vec<BatchOp>),
+    Step(Vec<BatchOp>),
+    PostAlgo(AlgoOrder),
+    CancelAlgo(String),
+    SetLeverage(SetLeverage),
+    AddMargin(AdjustMargin),
+    ReduceMargin(AdjustMargin),
+    Deposit(Transfer),
+    Withdraw(Transfer),
+    ClosePosition(ClosePosition),
+    GetOrder(String),
+    GetOpenOrders,
+    GetAccount,
+    GetFills(FillQuery),
     Close,
     Unknown(String),
 }
@@ -73,9 +152,84 @@ impl CommandParser {
             }
         } else if let Some(stripped) = message.strip_prefix("CANCEL_ORDER") {
             Ok(Command::CancelOrder(stripped.to_string()))
+        } else if let Some(stripped) = message.strip_prefix("CANCEL_ALL") {
+            if stripped.trim().is_empty() {
+                return Ok(Command::CancelAll(CancelAll::default()));
+            }
+            match sonic_rs::from_str::<CancelAll>(stripped) {
+                Ok(filter) => Ok(Command::CancelAll(filter)),
+                Err(e) => Err(format!("Error parsing cancel all: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("AMEND_ORDER") {
+            match sonic_rs::from_str::<AmendOrder>(stripped) {
+                Ok(amend) => Ok(Command::AmendOrder(amend)),
+                Err(e) => Err(format!("Error parsing amend: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("BATCH") {
+            match sonic_rs::from_str::<Vec<BatchOp>>(stripped) {
+                Ok(ops) => Ok(Command::Batch(ops)),
+                Err(e) => Err(format!("Error parsing batch: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("STEP") {
+            if stripped.trim().is_empty() {
+                return Ok(Command::Step(Vec::new()));
+            }
+            match sonic_rs::from_str::<Vec<BatchOp>>(stripped) {
+                Ok(ops) => Ok(Command::Step(ops)),
+                Err(e) => Err(format!("Error parsing step: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("POST_ALGO") {
+            match sonic_rs::from_str::<AlgoOrder>(stripped) {
+                Ok(algo) => Ok(Command::PostAlgo(algo)),
+                Err(e) => Err(format!("Error parsing algo: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("CANCEL_ALGO") {
+            Ok(Command::CancelAlgo(stripped.to_string()))
+        } else if let Some(stripped) = message.strip_prefix("SET_LEVERAGE") {
+            match sonic_rs::from_str::<SetLeverage>(stripped) {
+                Ok(request) => Ok(Command::SetLeverage(request)),
+                Err(e) => Err(format!("Error parsing leverage: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("ADD_MARGIN") {
+            match sonic_rs::from_str::<AdjustMargin>(stripped) {
+                Ok(request) => Ok(Command::AddMargin(request)),
+                Err(e) => Err(format!("Error parsing margin: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("REDUCE_MARGIN") {
+            match sonic_rs::from_str::<AdjustMargin>(stripped) {
+                Ok(request) => Ok(Command::ReduceMargin(request)),
+                Err(e) => Err(format!("Error parsing margin: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("DEPOSIT") {
+            match sonic_rs::from_str::<Transfer>(stripped) {
+                Ok(transfer) => Ok(Command::Deposit(transfer)),
+                Err(e) => Err(format!("Error parsing transfer: {}", e)),
+            }
+        } else if let Some(stripped) = message.strip_prefix("WITHDRAW") {
+            match sonic_rs::from_str::<Transfer>(stripped) {
+                Ok(transfer) => Ok(Command::Withdraw(transfer)),
+                Err(e) => Err(format!("Error parsing transfer: {}", e)),
+            }
         } else if let Some(stripped) = message.strip_prefix("CLOSE_POSITION") {
-            Ok(Command::ClosePosition(stripped.to_string()))
-        } else if message.starts_with("CLOSE") {
+            match sonic_rs::from_str::<ClosePosition>(stripped) {
+                Ok(request) => Ok(Command::ClosePosition(request)),
+                Err(e) => Err(format!("Error parsing close position: {}", e)),
+            }
+        } else if message.starts_with("GET_OPEN_ORDERS") {
+            Ok(Command::GetOpenOrders)
+        } else if let Some(stripped) = message.strip_prefix("GET_ORDER") {
+            Ok(Command::GetOrder(stripped.to_string()))
+        } else if message.starts_with("GET_ACCOUNT") {
+            Ok(Command::GetAccount)
+        } else if let Some(stripped) = message.strip_prefix("GET_FILLS") {
+            if stripped.trim().is_empty() {
+                return Ok(Command::GetFills(FillQuery::default()));
+            }
+            match sonic_rs::from_str::<FillQuery>(stripped) {
+                Ok(query) => Ok(Command::GetFills(query)),
+                Err(e) => Err(format!("Error parsing fills query: {}", e)),
+            }
+        } else if message.trim() == "CLOSE" {
             Ok(Command::Close)
         } else {
             Ok(Command::Unknown(message.to_string()))
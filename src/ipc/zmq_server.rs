@@ -2,6 +2,7 @@ use zmq::Context;
 use log::info;
 
 use crate::engine::ZileanV1;
+use crate::server::BacktestStatus;
 use super::traits::MessageResponder;
 use super::handlers::{ZmqResponder, ZileanCommandHandler};
 
@@ -59,8 +60,8 @@ impl ZileanV1{
             // 使用命令处理器处理其他命令
             match command_handler.handle_command_with_engine(self, &message) {
                 Ok(response) => {
-                    // 特殊处理CLOSE/TICK命令
-                    if message.starts_with("CLOSE") {
+                    // 特殊处理CLOSE/TICK命令，CLOSE_POSITION不结束回测
+                    if message.trim() == "CLOSE" {
                         let _ = message_responder.send_response(&response);
                         break;
                     }
@@ -69,7 +70,10 @@ impl ZileanV1{
                         log::error!("Failed to send response: {}", e);
                     }
 
-                    if message.starts_with("TICK") {
+                    // STEP只在操作成功时推进
+                    if message.starts_with("TICK")
+                        || (message.starts_with("STEP") && response.status == BacktestStatus::Ok)
+                    {
                         // 更新缓存的tick数据
                         if let Err(e) = command_handler.update_tick_cache(self).await {
                             log::error!("Failed to update tick cache: {}", e);
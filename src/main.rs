@@ -47,7 +47,7 @@ async fn main() {
         };
 
         if let Some(stripped) = message.strip_prefix("LAUNCH_BACKTEST") {
-            let bt_config: BtConfig = match sonic_rs::from_str(stripped) {
+            let mut bt_config: BtConfig = match sonic_rs::from_str(stripped) {
                 Ok(config) => config,
                 Err(e) => {
                     let response = sonic_rs::to_string(&BacktestResponse::bad_request(
@@ -58,11 +58,21 @@ async fn main() {
                     continue;
                 }
             };
+            bt_config.seed.get_or_insert_with(rand::random);
+            if let Err(e) = bt_config.validate().and_then(|_| bt_config.prepare(&zconfig)) {
+                let response = sonic_rs::to_string(&BacktestResponse::bad_request(
+                    "Backtest launched failed.".to_string() + e.as_str(),
+                ))
+                .unwrap();
+                let _ = responder.send(response.as_str(), 0);
+                continue;
+            }
             let backtest_id = zilean_server
-                .launch_backtest(bt_config, zconfig.clone())
+                .launch_backtest(bt_config.clone(), zconfig.clone())
                 .await;
+            info!("{} launched with {:?}", backtest_id, bt_config);
             let response =
-                sonic_rs::to_string(&BacktestResponse::normal_response(backtest_id)).unwrap();
+                sonic_rs::to_string(&BacktestResponse::launched(backtest_id, bt_config)).unwrap();
             let _ = responder.send(response.as_str(), 0);
         }
     }